@@ -1,8 +1,15 @@
-use ipg_core::game::{map::Map, GameExecutor, Planet, Player};
+use ipg_core::game::{
+    map::Map, DisconnectPolicy, GameClock, GameConfig, GameExecutor, Planet, Player,
+};
+use ipg_core::protocol::clock_sync::ClockSync;
 use ipg_core::protocol::messages::{
-    EnterGame, GameList, GameMetadata, GameMove, GameState, MessageType, SetName,
+    ChatMessage, ChatScope, CreateGame, EnterGame, Envelope, GameCreated, GameList, GameMetadata,
+    GameMove, GameQuery, GameState, InviteCode, MessageType, RequestId, SendChat, SetName,
+    Surrender, TimeSync, Visibility,
 };
 use js_sys;
+use serde_derive::Serialize;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
@@ -12,11 +19,14 @@ use self::game_render::GameRender;
 
 struct JoinedGame {
     exec: GameExecutor,
-    #[allow(unused)]
     metadata: GameMetadata,
     render: GameRender,
-    possesion_index: u32,
+    /// None while spectating
+    possesion_index: Option<u32>,
     selected_planet: Option<Planet>,
+    /// Set while waiting for a fresh copy of the game after the local
+    /// simulation failed. The game isn't stepped until it arrives.
+    resyncing: bool,
 }
 struct Waiting {
     metadata: GameMetadata,
@@ -47,12 +57,39 @@ impl ActiveGame {
     }
 }
 
+/// A request the server rejected, kept so the UI can report it to the player.
+#[derive(Serialize)]
+struct RequestError {
+    request_id: Option<RequestId>,
+    /// Kind of the rejected request, e.g. `GameMove`
+    request: Option<&'static str>,
+    message: String,
+}
+
 #[wasm_bindgen]
 pub struct GameClient {
     game_list: Vec<GameMetadata>,
+    /// Number of games matching the last query, which may be more than were sent
+    game_list_total: u32,
     current_game: ActiveGame,
     socket: WebSocket,
     maps: HashMap<String, Map>,
+    next_request_id: Cell<RequestId>,
+    // Requests that are awaiting an acknowledgement, by id
+    pending_requests: RefCell<HashMap<RequestId, &'static str>>,
+    request_errors: Vec<RequestError>,
+    clock_sync: ClockSync,
+    game_clock: Option<GameClock>,
+    chat_messages: Vec<ChatMessage>,
+    /// Invite codes of games created by (or shared with) this client, by game id
+    invite_codes: HashMap<String, InviteCode>,
+    /// The last game this client created
+    created_game: Option<GameMetadata>,
+}
+
+/// Reads the client's clock in milliseconds since the unix epoch.
+fn now() -> u128 {
+    js_sys::Date::now() as u128
 }
 
 pub struct RejoinCode {}
@@ -75,6 +112,11 @@ impl RejoinCode {
             .get_item(game_id)
             .expect("Couldn't save rejoine code.")
     }
+    pub fn remove(game_id: &str) {
+        RejoinCode::get_storage()
+            .remove_item(game_id)
+            .expect("Couldn't remove rejoin code.");
+    }
 }
 
 #[wasm_bindgen]
@@ -82,16 +124,61 @@ impl GameClient {
     pub fn new(socket: WebSocket) -> GameClient {
         GameClient {
             game_list: Vec::new(),
+            game_list_total: 0,
             current_game: ActiveGame::None,
             socket, // on_game_list: Vec::new()
             maps: HashMap::new(),
+            next_request_id: Cell::new(0),
+            pending_requests: RefCell::new(HashMap::new()),
+            request_errors: Vec::new(),
+            clock_sync: ClockSync::default(),
+            game_clock: None,
+            chat_messages: Vec::new(),
+            invite_codes: HashMap::new(),
+            created_game: None,
         }
     }
 
+    /// Sends a request to the server, tagged with a fresh request id so that
+    /// the server's acknowledgement or error can be matched up with it.
+    fn send_request(&self, message: MessageType) -> Result<RequestId, JsValue> {
+        let request_id = self.next_request_id.get();
+        self.next_request_id.set(request_id.wrapping_add(1));
+        self.pending_requests
+            .borrow_mut()
+            .insert(request_id, message.kind());
+        let envelope = Envelope {
+            request_id: Some(request_id),
+            message,
+        };
+        self.socket
+            .send_with_str(serde_json::to_string(&envelope).unwrap().as_str())?;
+        Ok(request_id)
+    }
+
     pub fn handle_message(&mut self, msg_body: String) -> Option<String> {
         log!("{}", msg_body.as_str());
-        let message = serde_json::from_str::<MessageType>(msg_body.as_str()).unwrap();
+        let Envelope {
+            request_id,
+            message,
+        } = serde_json::from_str::<Envelope>(msg_body.as_str()).unwrap();
         match message {
+            MessageType::Ack => {
+                if let Some(request_id) = request_id {
+                    self.pending_requests.borrow_mut().remove(&request_id);
+                }
+                Some("Ack".to_owned())
+            }
+            MessageType::Error(message) => {
+                let request = request_id
+                    .and_then(|request_id| self.pending_requests.borrow_mut().remove(&request_id));
+                self.request_errors.push(RequestError {
+                    request_id,
+                    request,
+                    message,
+                });
+                Some("Error".to_owned())
+            }
             MessageType::Pong => Some("Pong".to_string()),
             MessageType::NewGame(game_metadata) => {
                 self.game_list.push(game_metadata);
@@ -103,11 +190,54 @@ impl GameClient {
                 });
                 Some("GameList".to_string())
             }
-            MessageType::GameList(GameList { games }) => {
+            MessageType::GameList(GameList { games, total, .. }) => {
                 self.game_list = games;
+                self.game_list_total = total;
                 Some("GameList".to_string())
             }
-            MessageType::Time(_time) => Some("Time".to_owned()),
+            MessageType::UpdateGame(game_metadata) => {
+                if let ActiveGame::Waiting(ref mut waiting) = self.current_game {
+                    if waiting.metadata.game_id == game_metadata.game_id {
+                        waiting.metadata = game_metadata.clone();
+                    }
+                }
+                for game in self.game_list.iter_mut() {
+                    if game.game_id == game_metadata.game_id {
+                        *game = game_metadata.clone();
+                    }
+                }
+                Some("UpdateGame".to_owned())
+            }
+            MessageType::GameCreated(GameCreated { game, invite_code }) => {
+                self.invite_codes.insert(game.game_id.clone(), invite_code);
+                self.created_game = Some(game);
+                Some("GameCreated".to_owned())
+            }
+            MessageType::Kicked(_) => {
+                self.current_game = ActiveGame::None;
+                self.game_clock = None;
+                self.chat_messages
+                    .retain(|message| message.scope == ChatScope::Lobby);
+                Some("Kicked".to_owned())
+            }
+            MessageType::Time(reply) => {
+                if self.clock_sync.add_reply(&reply, now()) {
+                    self.send_time_sync();
+                }
+                Some("Time".to_owned())
+            }
+            MessageType::GameClock(clock) => {
+                self.game_clock = Some(clock);
+                Some("GameClock".to_owned())
+            }
+            MessageType::Chat(message) => {
+                self.chat_messages.push(message);
+                Some("Chat".to_owned())
+            }
+            MessageType::ChatHistory(messages) => {
+                self.chat_messages.extend(messages);
+                Some("Chat".to_owned())
+            }
             MessageType::MapList(map_list) => {
                 self.maps = map_list;
                 Some("MapList".to_owned())
@@ -123,24 +253,39 @@ impl GameClient {
                                 Some("GameState".to_string())
                             }
                             MessageType::Possession(possession) => {
-                                current.possesion_index = possession;
+                                current.possesion_index = Some(possession);
                                 Some("Possesion".to_string())
                             }
                             MessageType::Game(game) => {
                                 current.exec.set_game(game);
+                                current.resyncing = false;
                                 Some("Game".to_string())
                             }
                             MessageType::GamePlayers(players) => {
                                 current.exec.game.players = players;
                                 Some("GamePlayers".to_owned())
                             }
+                            MessageType::Eliminated(standing) => {
+                                if current.possesion_index == Some(standing.possession as u32) {
+                                    // Players who surrendered watch the rest of the game
+                                    current.possesion_index = None;
+                                    RejoinCode::remove(&current.metadata.game_id);
+                                }
+                                Some("Eliminated".to_owned())
+                            }
+                            MessageType::Arrival(_) => Some("Arrival".to_owned()),
+                            MessageType::Capture(_) => Some("Capture".to_owned()),
+                            MessageType::Heartbeat(_) => Some("Heartbeat".to_owned()),
                             _ => None,
                         }
                     }
                     ActiveGame::Waiting(ref mut waiting) => {
                         match message {
                             MessageType::EnterGame(EnterGame { rejoin_code, .. }) => {
-                                RejoinCode::set(&waiting.metadata.game_id, &rejoin_code.unwrap());
+                                // Spectators don't get a rejoin code
+                                if let Some(rejoin_code) = rejoin_code {
+                                    RejoinCode::set(&waiting.metadata.game_id, &rejoin_code);
+                                }
                                 Some("EnterGame".to_owned())
                             }
                             MessageType::Game(game) => {
@@ -158,10 +303,9 @@ impl GameClient {
                                     ),
                                     metadata: waiting.metadata,
                                     render: waiting.render,
-                                    possesion_index: waiting.possesion_index.expect(
-                                        "Cannot start game until posession index has been sent",
-                                    ),
+                                    possesion_index: waiting.possesion_index,
                                     selected_planet: None,
+                                    resyncing: false,
                                 });
                                 Some("Game".to_string())
                             }
@@ -205,11 +349,53 @@ impl GameClient {
     //     self.game_list.clone().into_boxed_slice()
     // }
 
+    /// Number of games matching the last query, including those not yet sent.
+    pub fn game_list_total(&self) -> u32 {
+        self.game_list_total
+    }
+
+    /// Replaces the game list with a filtered page of the server's games, e.g.
+    /// `{ status: "Waiting", open_slots: true, offset: 50 }`.
+    pub fn query_games(&self, query: JsValue) -> Result<(), JsValue> {
+        let query = query
+            .into_serde::<GameQuery>()
+            .map_err(|e| JsValue::from(e.to_string()))?;
+        self.send_request(MessageType::QueryGames(query))?;
+        Ok(())
+    }
+
+    /// Returns the errors the server has reported since the last call, each with
+    /// the id and kind of the request that caused it (if known).
+    pub fn take_request_errors(&mut self) -> JsValue {
+        let errors = std::mem::take(&mut self.request_errors);
+        JsValue::from_serde(&errors).unwrap()
+    }
+
+    /// Gets the chat messages received from the lobby and the current game, oldest first.
+    pub fn get_chat_messages(&self) -> JsValue {
+        JsValue::from_serde(&self.chat_messages).unwrap()
+    }
+
+    pub fn send_lobby_chat(&self, text: String) -> Result<(), JsValue> {
+        self.send_request(MessageType::SendChat(SendChat {
+            scope: ChatScope::Lobby,
+            text,
+        }))?;
+        Ok(())
+    }
+
+    pub fn send_game_chat(&self, text: String) -> Result<(), JsValue> {
+        self.send_request(MessageType::SendChat(SendChat {
+            scope: ChatScope::Game,
+            text,
+        }))?;
+        Ok(())
+    }
+
     pub fn create_game(&self) {}
 
     pub fn set_name(&self, name: String) {
-        let message = serde_json::to_string(&MessageType::SetName(SetName { name })).unwrap();
-        let _ = self.socket.send_with_str(message.as_str());
+        let _ = self.send_request(MessageType::SetName(SetName { name }));
         log!("Name_set");
     }
 
@@ -227,33 +413,77 @@ impl GameClient {
     //         .and_then(|state| state.game.state.as_ref().map(|s| s.start_time))
     // }
 
-    pub fn get_clock_offset(&self) {
-        let window = web_sys::window().expect("Should have a window in this context");
-        let _time = window
-            .performance()
-            .expect("Unable to access performance")
-            .now() as u128;
-        let message = serde_json::to_string(&MessageType::Time(0)).unwrap();
+    fn send_time_sync(&self) {
+        let message =
+            serde_json::to_string(&Envelope::from(MessageType::Time(TimeSync::request(now()))))
+                .unwrap();
         let _ = self.socket.send_with_str(message.as_str());
     }
 
+    /// Starts synchronising with the server's clock. The exchange is repeated
+    /// several times in the background as replies arrive.
+    pub fn sync_clock(&mut self) {
+        self.clock_sync.reset();
+        self.send_time_sync();
+    }
+
+    /// Milliseconds to add to the local clock to get the server's clock,
+    /// if the clock has been synchronised.
+    pub fn get_clock_offset(&self) -> Option<f64> {
+        self.clock_sync.offset()
+    }
+
+    /// Round trip latency to the server in milliseconds.
+    pub fn get_latency(&self) -> Option<f64> {
+        self.clock_sync.round_trip()
+    }
+
+    /// The current tick of the joined game according to the server's clock.
+    pub fn get_game_tick(&self) -> Option<u32> {
+        self.clock_sync.game_tick(self.game_clock.as_ref()?, now())
+    }
+
+    /// The last game this client created, which it can enter even if unlisted.
+    pub fn get_created_game(&self) -> JsValue {
+        JsValue::from_serde(&self.created_game).unwrap()
+    }
+
+    /// The invite code of a game, if this client created it or was invited.
+    pub fn get_invite_code(&self, game_id: String) -> Option<String> {
+        self.invite_codes.get(&game_id).cloned()
+    }
+
     pub fn enter_game(
         &mut self,
         game_metadata: JsValue,
         // HTML pointers needed to render the game
         canvas_top: HtmlCanvasElement,
         canvas_bottom: HtmlCanvasElement,
+        password: Option<String>,
+        invite_code: Option<String>,
+        spectate: bool,
     ) -> Result<(), JsValue> {
         if let Ok(game_metadata) =
             game_metadata.into_serde() as Result<GameMetadata, serde_json::Error>
         {
             let rejoin_code = RejoinCode::get(&game_metadata.game_id);
-            let message = serde_json::to_string(&MessageType::EnterGame(EnterGame {
+            if let Some(invite_code) = &invite_code {
+                self.invite_codes
+                    .insert(game_metadata.game_id.clone(), invite_code.clone());
+            }
+            let invite_code = self.invite_codes.get(&game_metadata.game_id).cloned();
+            self.game_clock = None;
+            // The server sends the new game's history on entry
+            self.chat_messages
+                .retain(|message| message.scope == ChatScope::Lobby);
+            self.sync_clock();
+            self.send_request(MessageType::EnterGame(EnterGame {
                 game_id: game_metadata.game_id.to_owned(),
                 rejoin_code,
-            }))
-            .unwrap();
-            let _ = self.socket.send_with_str(message.as_str());
+                password,
+                invite_code,
+                spectate,
+            }))?;
             self.current_game = ActiveGame::Waiting(Waiting {
                 metadata: game_metadata,
                 players: None,
@@ -265,8 +495,66 @@ impl GameClient {
     }
 
     pub fn start_game(&self) -> Result<(), JsValue> {
-        let message = serde_json::to_string(&MessageType::StartGame).unwrap();
-        self.socket.send_with_str(message.as_str())
+        self.send_request(MessageType::StartGame)?;
+        Ok(())
+    }
+
+    pub fn set_ready(&self, ready: bool) -> Result<(), JsValue> {
+        self.send_request(MessageType::SetReady(ready))?;
+        Ok(())
+    }
+
+    /// Host only: removes a player from the game before it starts.
+    pub fn kick_player(&self, possession: u32) -> Result<(), JsValue> {
+        self.send_request(MessageType::KickPlayer(possession))?;
+        Ok(())
+    }
+
+    /// Host only: makes another player the host.
+    pub fn transfer_host(&self, possession: u32) -> Result<(), JsValue> {
+        self.send_request(MessageType::TransferHost(possession))?;
+        Ok(())
+    }
+
+    /// Host only: changes the map and player count before the game starts.
+    pub fn update_game_settings(&self, map_id: String, min_players: u32) -> Result<(), JsValue> {
+        // Keep the policy the game was created with
+        let disconnect_policy = match &self.current_game {
+            ActiveGame::Waiting(waiting) => waiting.metadata.config.disconnect_policy,
+            _ => DisconnectPolicy::default(),
+        };
+        self.send_request(MessageType::UpdateGameSettings(CreateGame {
+            map_id,
+            config: GameConfig {
+                min_players,
+                disconnect_policy,
+            },
+            // Ignored by the server, visibility is fixed at creation
+            visibility: Visibility::default(),
+        }))?;
+        Ok(())
+    }
+
+    /// Whether this client hosts the game it is waiting in.
+    pub fn is_host(&self) -> bool {
+        match &self.current_game {
+            ActiveGame::Waiting(Waiting {
+                players: Some(players),
+                possesion_index: Some(possession),
+                ..
+            }) => players
+                .iter()
+                .any(|player| player.host && player.possession as u32 == *possession),
+            _ => false,
+        }
+    }
+
+    /// Gets the metadata of the game being waited on, which the host may change.
+    pub fn get_waiting_game(&self) -> JsValue {
+        match &self.current_game {
+            ActiveGame::Waiting(waiting) => JsValue::from_serde(&waiting.metadata).unwrap(),
+            _ => JsValue::NULL,
+        }
     }
 
     pub fn set_render_target(
@@ -309,6 +597,32 @@ impl GameClient {
             })
     }
 
+    /// Gives up the game, leaving the player's planets to the heir or making
+    /// them neutral.
+    pub fn surrender(&self, heir: Option<u32>) -> Result<(), JsValue> {
+        self.send_request(MessageType::Surrender(Surrender { heir }))?;
+        Ok(())
+    }
+
+    /// The client's possession in the game it is playing, None while watching.
+    pub fn get_possession(&self) -> Option<u32> {
+        match &self.current_game {
+            ActiveGame::Joined(current) => current.possesion_index,
+            ActiveGame::Waiting(waiting) => waiting.possesion_index,
+            ActiveGame::None => None,
+        }
+    }
+
+    /// Players who are out of the current game, and its winner once there is one.
+    pub fn get_standings(&self) -> JsValue {
+        match &self.current_game {
+            ActiveGame::Joined(current) => {
+                JsValue::from_serde(&current.exec.game.standings).unwrap()
+            }
+            _ => JsValue::NULL,
+        }
+    }
+
     pub fn get_player_list(&self) -> Option<js_sys::Array> {
         match &self.current_game {
             ActiveGame::Joined(current) => Some(&current.exec.game.players),
@@ -326,15 +640,23 @@ impl GameClient {
         })
     }
 
-    pub fn render_game_frame(&mut self, mut time: u32) -> Result<(), JsValue> {
+    pub fn render_game_frame(&mut self) -> Result<(), JsValue> {
+        let tick = self.get_game_tick();
+        let mut resync = false;
         if let ActiveGame::Joined(current) = &mut self.current_game {
-            if let Some(ref galaxy) = current.exec.game.state {
-                // temprorary in lieu of proper sync
-                time = galaxy.time.max(time);
-                if time < galaxy.time {
-                    return Err(JsValue::from("Cannot render frames from the past."));
-                };
-                current.exec.step_to(time);
+            if let (Some(ref galaxy), false) = (&current.exec.game.state, current.resyncing) {
+                // Until the clock is synchronised the game is rendered as
+                // last sent. The server may also have stepped the state a
+                // little past our estimate of its clock, so never step back.
+                let time = tick.unwrap_or(galaxy.time).max(galaxy.time);
+                if let Err(error) = current.exec.step_to(time) {
+                    log!("Simulation failed, resyncing: {}", error);
+                    current.resyncing = true;
+                    resync = true;
+                }
+            }
+            if resync {
+                self.send_request(MessageType::SyncGame)?;
             }
             if let ActiveGame::Joined(current) = &mut self.current_game {
                 current
@@ -414,7 +736,7 @@ impl GameClient {
                     let new_selection = new_selection.clone();
                     let owned_by_player = new_selection
                         .possession
-                        .map(|p| p as u32 == game.possesion_index)
+                        .map(|p| Some(p as u32) == game.possesion_index)
                         .unwrap_or(false);
                     if owned_by_player {
                         if let ActiveGame::Joined(ref mut game) = self.current_game {
@@ -435,11 +757,9 @@ impl GameClient {
     }
 
     fn make_move(&self, from: &Planet, to: &Planet) {
-        let message = serde_json::to_string(&MessageType::GameMove(GameMove {
+        let _ = self.send_request(MessageType::GameMove(GameMove {
             to: to.index as u16,
             from: from.index as u16,
-        }))
-        .unwrap();
-        let _ = self.socket.send_with_str(message.as_str());
+        }));
     }
 }
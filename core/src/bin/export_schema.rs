@@ -0,0 +1,11 @@
+//! Prints the JSON Schema of the wire protocol.
+//!
+//! The TypeScript UI mirrors these types by hand, so the committed copy is
+//! regenerated whenever the protocol changes:
+//!
+//! ```sh
+//! cargo run -p ipg_core --bin export_schema > core/schema/protocol.json
+//! ```
+fn main() {
+    println!("{}", ipg_core::protocol::schema());
+}
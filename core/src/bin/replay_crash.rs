@@ -0,0 +1,28 @@
+//! Replays a crash dump written by the server, to reproduce the crash under
+//! a debugger:
+//!
+//! ```sh
+//! cargo run -p ipg_core --bin replay_crash -- crash_dumps/<dump>.json
+//! ```
+use ipg_core::game::crash::CrashDump;
+use std::fs;
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .expect("Usage: replay_crash <crash dump>");
+    let contents = fs::read_to_string(&path).expect("Unable to read the crash dump");
+    let dump: CrashDump = serde_json::from_str(&contents).expect("Invalid crash dump");
+    println!(
+        "Game {} crashed at tick {}: {}",
+        dump.game_id, dump.tick, dump.message
+    );
+    println!("Offending input: {:?}", dump.offending_input());
+    println!("Replaying {} inputs...", dump.inputs.len());
+    let (executor, skipped) = dump.replay();
+    for reason in skipped {
+        println!("{}", reason);
+    }
+    let tick = executor.game.state.as_ref().map_or(0, |galaxy| galaxy.time);
+    println!("The replay finished at tick {} without crashing.", tick);
+}
@@ -0,0 +1,29 @@
+use super::{Galaxy, Planet};
+use std::cmp::Ordering;
+
+/// Picks a move for a bot playing the given possession: sends half the ships
+/// on its strongest planet to the nearest planet they can take over. Returns
+/// the indices of the planets to move from and to, or None if there is no
+/// such planet.
+pub fn choose_move(galaxy: &Galaxy, possession: usize) -> Option<(u16, u16)> {
+    let from = galaxy
+        .planets
+        .iter()
+        .filter(|planet| planet.possession == Some(possession))
+        .max_by(|a, b| a.value.partial_cmp(&b.value).unwrap_or(Ordering::Equal))?;
+    let armada_size = (from.value as u32 / 2) as f32;
+    let to = galaxy
+        .planets
+        .iter()
+        .filter(|planet| planet.possession != Some(possession) && planet.value < armada_size)
+        .min_by(|a, b| {
+            distance(from, a)
+                .partial_cmp(&distance(from, b))
+                .unwrap_or(Ordering::Equal)
+        })?;
+    Some((from.index as u16, to.index as u16))
+}
+
+fn distance(a: &Planet, b: &Planet) -> f32 {
+    ((a.x as f32 - b.x as f32).powf(2.0) + (a.y as f32 - b.y as f32).powf(2.0)).sqrt()
+}
@@ -0,0 +1,80 @@
+use crate::game::{GameExecutor, GameSnapshot};
+
+/// Something a game was given to process, recorded so a crash can be replayed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum GameInput {
+    /// The galaxy was stepped forward to a tick
+    Step(u32),
+    /// A player sent ships from one planet to another
+    Move {
+        possession: usize,
+        from: u16,
+        to: u16,
+        time: u32,
+    },
+    /// Anything else, by the name of the request. These can't be replayed.
+    Request(String),
+}
+
+/// Everything needed to reproduce a game crashing: the game as of its last
+/// checkpoint, and every input it was given after that.
+#[derive(Serialize, Deserialize)]
+pub struct CrashDump {
+    pub game_id: String,
+    /// What the game panicked with
+    pub message: String,
+    /// Tick the galaxy had reached when the game crashed
+    pub tick: u32,
+    /// Server time of the crash, in milliseconds since the unix epoch
+    pub crashed_at: u128,
+    pub checkpoint: GameSnapshot,
+    /// Inputs since the checkpoint, oldest first. The last one crashed the game.
+    pub inputs: Vec<GameInput>,
+}
+
+impl CrashDump {
+    /// The input the game crashed on.
+    pub fn offending_input(&self) -> Option<&GameInput> {
+        self.inputs.last()
+    }
+
+    /// Restores the checkpoint and feeds it the recorded inputs, which
+    /// panics the same way the game did if the bug is in the simulation.
+    /// Returns the game as it was after the last input, along with why any
+    /// inputs were skipped.
+    pub fn replay(&self) -> (GameExecutor, Vec<String>) {
+        let mut executor = GameExecutor::from_snapshot(self.checkpoint.clone());
+        let mut skipped = Vec::new();
+        for input in &self.inputs {
+            if let Err(error) = executor.replay_input(input) {
+                skipped.push(format!("Skipped {:?}: {}", input, error));
+            }
+        }
+        (executor, skipped)
+    }
+}
+
+impl GameExecutor {
+    fn replay_input(&mut self, input: &GameInput) -> Result<(), String> {
+        match input {
+            GameInput::Step(time) => Ok(self.step_to(*time)?),
+            GameInput::Move {
+                possession,
+                from,
+                to,
+                time,
+            } => {
+                let player = self
+                    .game
+                    .players
+                    .iter()
+                    .find(|player| player.possession == *possession)
+                    .cloned()
+                    .ok_or_else(|| format!("There is no player {} in this game.", possession))?;
+                let game_move = self.create_move_at(*from, *to, *time)?;
+                Ok(self.add_move(&player, game_move)?)
+            }
+            GameInput::Request(request) => Err(format!("{} requests can't be replayed.", request)),
+        }
+    }
+}
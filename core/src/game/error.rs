@@ -0,0 +1,96 @@
+use std::fmt;
+
+/// Why the simulation couldn't process a request.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SimulationError {
+    /// The request breaks the rules of the game, e.g. moving from another
+    /// player's planet. The galaxy is left as it was.
+    Rejected(String),
+    /// The game hasn't started, so there is no galaxy yet
+    NotStarted,
+    /// A planet index outside the galaxy
+    UnknownPlanet(usize),
+    /// The map can't be played with the game's players
+    InvalidMap(String),
+    /// Stepping to a tick before the galaxy's current time
+    TimeTravel { target_time: u32, galaxy_time: u32 },
+    /// A move applied at a different tick than it was made at
+    MoveOutOfOrder { move_time: u32, galaxy_time: u32 },
+    /// Ships scheduled to land before the galaxy's current time
+    LateBucket { bucket_time: u32, galaxy_time: u32 },
+    /// A ship landing before the first tick its move was scheduled from,
+    /// which happens when the distance it has to travel comes out negative
+    EarlyArrival {
+        arrival: u32,
+        first_bucket_time: u32,
+    },
+    /// Ships sent from a planet without an owner
+    NeutralMove,
+}
+
+impl SimulationError {
+    /// Whether the error comes from inconsistent state rather than a bad
+    /// request. The galaxy may have been partially updated, so it can't be
+    /// trusted until it is replaced with a fresh copy.
+    pub fn is_internal(&self) -> bool {
+        !matches!(
+            self,
+            SimulationError::Rejected(_)
+                | SimulationError::NotStarted
+                | SimulationError::UnknownPlanet(_)
+                | SimulationError::InvalidMap(_)
+        )
+    }
+}
+
+impl fmt::Display for SimulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimulationError::Rejected(reason) => write!(f, "{}", reason),
+            SimulationError::NotStarted => write!(f, "Game has not been started."),
+            SimulationError::UnknownPlanet(index) => write!(f, "There is no planet {}.", index),
+            SimulationError::InvalidMap(reason) => write!(f, "Invalid map: {}", reason),
+            SimulationError::TimeTravel {
+                target_time,
+                galaxy_time,
+            } => write!(
+                f,
+                "Cannot step into the past! Target time: {} is behind galaxy time: {}",
+                target_time, galaxy_time
+            ),
+            SimulationError::MoveOutOfOrder {
+                move_time,
+                galaxy_time,
+            } => write!(
+                f,
+                "A move made at {} was processed at galaxy time {}.",
+                move_time, galaxy_time
+            ),
+            SimulationError::LateBucket {
+                bucket_time,
+                galaxy_time,
+            } => write!(
+                f,
+                "Ships due at {} landed late, at galaxy time {}.",
+                bucket_time, galaxy_time
+            ),
+            SimulationError::EarlyArrival {
+                arrival,
+                first_bucket_time,
+            } => write!(
+                f,
+                "A ship arrived at {}, before its move's first arrival at {}.",
+                arrival, first_bucket_time
+            ),
+            SimulationError::NeutralMove => write!(f, "Neutral planets cannot make moves."),
+        }
+    }
+}
+
+impl std::error::Error for SimulationError {}
+
+impl From<SimulationError> for String {
+    fn from(error: SimulationError) -> String {
+        error.to_string()
+    }
+}
@@ -1,6 +1,9 @@
 use crate::game;
+use crate::game::SimulationError;
+use schemars::JsonSchema;
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+#[schemars(rename = "MapPlanet")]
 pub struct Planet {
     pub x: u32,
     pub y: u32,
@@ -10,14 +13,14 @@ pub struct Planet {
     pub multiplier: f32,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
 pub struct MapSize {
     pub x: u32,
     pub y: u32,
 }
 
 /// Represents the Inter Planet Game map format (v0.4)
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
 pub struct Map {
     pub size: MapSize,
     pub name: String,
@@ -47,22 +50,22 @@ impl Map {
     /// }]));
     /// ```
 
-    pub fn to_galaxy(&self, players: &mut Vec<game::Player>) -> Result<game::Galaxy, String> {
-        if players.len() < 2 {
-            return Err(String::from(
-                "Invalid map configuration. At least two players are required to create a galaxy.",
-            ));
-        }
-        let planets: Result<Vec<game::Planet>,String> = self.planets.iter().enumerate().map(|(index,planet)| {
-            let possesion = match planet.possession.get(players.len() - 2) {
+    pub fn to_galaxy(&self, players: &mut [game::Player]) -> Result<game::Galaxy, SimulationError> {
+        let column = players.len().checked_sub(2).ok_or_else(|| {
+            SimulationError::InvalidMap(String::from(
+                "At least two players are required to create a galaxy.",
+            ))
+        })?;
+        let planets: Result<Vec<game::Planet>,SimulationError> = self.planets.iter().enumerate().map(|(index,planet)| {
+            let possesion = match planet.possession.get(column) {
                 Some(0) => Ok(None),
                 Some(possesion_index) => match players.get_mut(*possesion_index as usize - 1) {
                     Some(player) => {
                         Ok(Some(player))
                     },
-                    None => Err(format!("Error encoutnered parsing map. A planet's possessions property specifiies player {} of {} players.",possesion_index - 1, players.len() ))
+                    None => Err(SimulationError::InvalidMap(format!("A planet's possessions property specifiies player {} of {} players.",possesion_index - 1, players.len() )))
                 },
-                None => Err(String::from("Error encoutnered parsing map. Planet's possessions property does not support the seleced player count. The map is corrupted."))
+                None => Err(SimulationError::InvalidMap(String::from("Planet's possessions property does not support the seleced player count. The map is corrupted.")))
             };
             Ok(game::Planet {
                 index,
@@ -83,6 +86,8 @@ impl Map {
     }
 
     pub fn max_players(self) -> usize {
-        self.planets[0].possession.len()
+        self.planets
+            .first()
+            .map_or(0, |planet| planet.possession.len())
     }
 }
@@ -1,7 +1,13 @@
+pub mod bot;
+pub mod crash;
+mod error;
 pub mod map;
+use crate::protocol::messages::{ChatMessage, GameMetadata, GameStatus};
+pub use error::SimulationError;
 use rand::Rng;
 use rand_xoshiro::rand_core::SeedableRng;
 use rand_xoshiro::Xoshiro128StarStar;
+use schemars::JsonSchema;
 use std::collections::VecDeque;
 use std::f32::consts::PI;
 use std::sync::Arc;
@@ -13,10 +19,22 @@ cfg_if! {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Player {
     pub possession: usize,
     pub name: String,
+    /// Set by the player while waiting for the game to start
+    #[serde(default)]
+    pub ready: bool,
+    /// The host starts the game and manages its players and settings
+    #[serde(default)]
+    pub host: bool,
+    /// The player's seat is being held for them after they lost their connection
+    #[serde(default)]
+    pub disconnected: bool,
+    /// A bot is playing in the player's place
+    #[serde(default)]
+    pub bot: bool,
     // state: PlayerState
 }
 
@@ -27,7 +45,7 @@ enum PlayerState {
     Gone,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Planet {
     // If use a planet deseralized from an untrusted source, an attacker could
     // undermine the integrety of the game by changing the planet's values
@@ -40,7 +58,7 @@ pub struct Planet {
     pub possession: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Move {
     pub from: Planet,
     pub to: Planet,
@@ -48,7 +66,7 @@ pub struct Move {
     pub start_time: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Galaxy {
     pub time: u32, //?
     pub planets: Vec<Planet>,
@@ -66,21 +84,118 @@ impl Galaxy {
         }
         return None;
     }
+
+    /// The only player left holding planets or ships, once everyone else has
+    /// been wiped out.
+    pub fn winner(&self) -> Option<usize> {
+        let in_flight = self
+            .moves
+            .iter()
+            .filter(|game_move| game_move.end_time() > self.time)
+            .filter_map(|game_move| game_move.from.possession);
+        let mut owners = self
+            .planets
+            .iter()
+            .filter_map(|planet| planet.possession)
+            .chain(in_flight);
+        let first = owners.next()?;
+        if owners.all(|owner| owner == first) {
+            Some(first)
+        } else {
+            None
+        }
+    }
 }
 
 //#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Game {
     pub map: map::Map,
     pub state: Option<Galaxy>,
     pub players: Vec<Player>,
     pub config: GameConfig,
+    /// Players who are out of the game, and the winner once there is one
+    #[serde(default)]
+    pub standings: Vec<Standing>,
+}
+
+/// Where a player finished in a game.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Standing {
+    pub possession: usize,
+    pub name: String,
+    /// 1 for the winner
+    pub placement: u32,
 }
 //assert_impl_all!(Game: Sync, Send);
 
-#[derive(Serialize, Deserialize, Clone)]
+/// A fleet that has finished landing on its target.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Arrival {
+    /// Tick the last ship of the fleet landed on
+    pub time: u32,
+    pub from: usize,
+    pub to: usize,
+    pub possession: Option<usize>,
+    pub armada_size: u32,
+}
+
+/// A planet taken over by another player.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Capture {
+    pub time: u32,
+    pub planet: usize,
+    /// The previous owner, or None if the planet was neutral
+    pub from: Option<usize>,
+    pub to: usize,
+}
+
+/// The planets of a running game at a moment in time, which clients can
+/// check their own copy of the galaxy against.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Heartbeat {
+    pub time: u32,
+    pub planets: Vec<PlanetStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PlanetStatus {
+    pub possession: Option<usize>,
+    pub value: f32,
+}
+
+/// Describes how server time maps onto game ticks.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct GameClock {
+    /// Server time the game started at, in milliseconds since the unix epoch
+    pub start_time: u128,
+    /// Length of a single game tick in milliseconds
+    pub tick_length: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct GameConfig {
     pub min_players: u32,
+    #[serde(default)]
+    pub disconnect_policy: DisconnectPolicy,
+}
+
+/// What happens to a player's seat once they have been disconnected for
+/// longer than the server's grace period.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, JsonSchema)]
+pub enum DisconnectPolicy {
+    /// The player leaves the game, leaving their planets without a commander
+    Forfeit,
+    /// The player leaves the game and their planets become neutral
+    Neutral,
+    /// A bot plays in the player's place until they come back
+    Bot,
+}
+
+impl Default for DisconnectPolicy {
+    fn default() -> Self {
+        DisconnectPolicy::Forfeit
+    }
 }
 
 impl Game {
@@ -90,17 +205,18 @@ impl Game {
             players: Vec::new(),
             state: None,
             config,
+            standings: Vec::new(),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct PlanetDelta {
     magnitude: u32,
     possession: u32,
 }
 
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 struct ModBucket {
     pub time: u32,
     pub deltas_by_planet: Vec<Vec<PlanetDelta>>,
@@ -110,6 +226,7 @@ type ModBuckets = VecDeque<Option<ModBucket>>;
 
 pub struct GameExecutor {
     pub start_time: u128,
+    pub tick_length: u32,
     pub game: Game,
     pub event_source: GameEventSource,
     completed_move_idx: usize,
@@ -118,28 +235,74 @@ pub struct GameExecutor {
     pub game_id: String,
 }
 
+/// Everything needed to bring a game back after the server restarts.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GameSnapshot {
+    pub game_id: String,
+    pub game: Game,
+    /// Milliseconds the game had been running for when the snapshot was
+    /// taken, so its clock picks up where it left off
+    pub elapsed: u128,
+    pub tick_length: u32,
+    completed_move_idx: usize,
+    modification_buckets: ModBuckets,
+}
+
 pub enum GameEvent {
     Player(Arc<Player>),
     PlayerLeave(Player),
+    /// A player was removed by the host
+    Kick(Player),
+    /// Ready flags or the host changed
+    PlayersChanged,
+    /// The host changed the map or configuration
+    SettingsChanged,
+    /// The game's description for the lobby changed
+    Metadata(GameMetadata),
+    /// A player gave up their seat, which may have changed the galaxy
+    Forfeit(Player),
+    /// A player is out of a running game
+    Eliminated(Standing),
     Move(Move),
-    Start,
+    Start(GameClock),
+    Chat(ChatMessage),
+    /// A fleet finished landing on its target
+    Arrival(Arrival),
+    /// A planet changed hands
+    Capture(Capture),
+    /// The current state of the planets, sent regularly while the game runs
+    Heartbeat(Heartbeat),
+    /// The game stopped after an error on the server
+    Crashed(String),
 }
 
+/// Identifies an event handler, so it can be removed again
+pub type HandlerId = usize;
+
 #[derive(Default)]
 pub struct GameEventSource {
-    pub handlers: Vec<Box<dyn FnMut(&GameEvent, &mut Game) -> () + Send + Sync>>,
+    pub handlers: Vec<(HandlerId, Box<dyn FnMut(&GameEvent, &mut Game) -> () + Send + Sync>)>,
+    next_handler_id: HandlerId,
 }
 
 impl GameEventSource {
-    pub fn on_event<H>(&mut self, handler: Box<H>)
+    pub fn on_event<H>(&mut self, handler: Box<H>) -> HandlerId
     where
         H: FnMut(&GameEvent, &mut Game) -> () + Send + Sync + 'static,
     {
-        self.handlers.push(handler);
+        let id = self.next_handler_id;
+        self.next_handler_id += 1;
+        self.handlers.push((id, handler));
+        id
+    }
+
+    /// Stops the handler receiving events.
+    pub fn remove_handler(&mut self, id: HandlerId) {
+        self.handlers.retain(|(handler_id, _)| *handler_id != id);
     }
 
     pub fn emit_event(&mut self, event: GameEvent, game: &mut Game) {
-        for handler in &mut self.handlers {
+        for (_, handler) in &mut self.handlers {
             handler(&event, game);
         }
     }
@@ -147,8 +310,10 @@ impl GameEventSource {
 
 const TICKS_PER_SHIP: u32 = 3600;
 pub const SHIP_SPEED: f32 = 0.5f32;
+/// Default length of a game tick in milliseconds
+pub const TICK_LENGTH: u32 = 17;
 
-fn get_millis() -> u128 {
+pub fn get_millis() -> u128 {
     SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
@@ -176,14 +341,14 @@ impl Move {
 
     pub fn end_time(&self) -> u32 {
         let dist = self.dist() + self.from.radius + self.to.radius;
-        (dist / SHIP_SPEED) as u32 + self.start_time
+        ((dist / SHIP_SPEED) as u32).saturating_add(self.start_time)
     }
 
     pub fn first_arrival_time(&self) -> u32 {
         let dist = self.dist() - self.from.radius - self.to.radius;
         // If the planets are overlapping, the distance will be negative.
         // This can result in an overflow therefore it is clamped to 0 avoid crashes.
-        (dist / SHIP_SPEED).max(0f32) as u32 + self.start_time
+        ((dist / SHIP_SPEED).max(0f32) as u32).saturating_add(self.start_time)
     }
 }
 
@@ -191,6 +356,7 @@ impl GameExecutor {
     pub fn from_game(game: Game, game_id: String) -> GameExecutor {
         GameExecutor {
             start_time: 0,
+            tick_length: TICK_LENGTH,
             game,
             event_source: GameEventSource::default(),
             completed_move_idx: 0,
@@ -199,6 +365,41 @@ impl GameExecutor {
         }
     }
 
+    /// Captures the game, including ships still in flight.
+    pub fn snapshot(&self) -> GameSnapshot {
+        GameSnapshot {
+            game_id: self.game_id.clone(),
+            game: self.game.clone(),
+            elapsed: match self.game.state {
+                Some(_) => get_millis().saturating_sub(self.start_time),
+                None => 0,
+            },
+            tick_length: self.tick_length,
+            completed_move_idx: self.completed_move_idx,
+            modification_buckets: self.modification_buckets.clone(),
+        }
+    }
+
+    /// Restores a game from a snapshot. Event handlers are not part of the
+    /// snapshot, so they start out empty, and every player is disconnected
+    /// until they rejoin.
+    pub fn from_snapshot(snapshot: GameSnapshot) -> GameExecutor {
+        let now = get_millis();
+        let mut game = snapshot.game;
+        for player in game.players.iter_mut().filter(|player| !player.bot) {
+            player.disconnected = true;
+        }
+        GameExecutor {
+            start_time: now.saturating_sub(snapshot.elapsed),
+            tick_length: snapshot.tick_length,
+            game,
+            event_source: GameEventSource::default(),
+            completed_move_idx: snapshot.completed_move_idx,
+            modification_buckets: snapshot.modification_buckets,
+            game_id: snapshot.game_id,
+        }
+    }
+
     /// Assumes that game is a later state of the current game.
     pub fn set_game(&mut self, game: Game) {
         // All processing state should now support old game state insertion.
@@ -212,9 +413,27 @@ impl GameExecutor {
         self.game = game;
     }
 
+    fn has_possession(&self, possession: usize) -> bool {
+        self.game
+            .players
+            .iter()
+            .any(|player| player.possession == possession)
+    }
+
+    /// Adds a player, keeping their possession if it is free (e.g. when
+    /// rejoining) and otherwise giving them the lowest free one. The first
+    /// player to join an empty game becomes its host.
     pub fn add_player(&mut self, mut player: Player) -> Result<Player, String> {
         if self.game.map.planets[0].possession.len() > self.game.players.len() {
-            player.possession = self.game.players.len();
+            if self.has_possession(player.possession) {
+                player.possession = (0..)
+                    .find(|possession| !self.has_possession(*possession))
+                    .unwrap();
+            }
+            player.ready = false;
+            player.host = !self.game.players.iter().any(|p| p.host);
+            player.disconnected = false;
+            player.bot = false;
             let player_cpy = player.clone();
             self.game.players.push(player);
             self.event_source.emit_event(
@@ -228,9 +447,7 @@ impl GameExecutor {
     }
 
     pub fn remove_player(&mut self, player: &Player) {
-        self.game
-            .players
-            .retain(|p| p.possession != player.possession);
+        self.take_player(player.possession);
         // We don't update the indecies -- since a player leaving should not suddenly reassign everyone.
         // Future enhancement would be to find a spectator if available, and use them as a subtitute.
 
@@ -238,16 +455,457 @@ impl GameExecutor {
             .emit_event(GameEvent::PlayerLeave(player.clone()), &mut self.game);
     }
 
-    pub fn start_game(&mut self) -> Result<(), String> {
+    /// Removes a player from the game at the host's request.
+    pub fn kick_player(&mut self, possession: usize) -> Result<Player, String> {
         if self.game.state.is_some() {
-            Err("Cannot start game, it has already started.".to_owned())
+            return Err("Players cannot be kicked once the game has started.".to_owned());
+        }
+        let player = self
+            .take_player(possession)
+            .ok_or_else(|| format!("There is no player {} in this game.", possession))?;
+        self.event_source
+            .emit_event(GameEvent::Kick(player.clone()), &mut self.game);
+        Ok(player)
+    }
+
+    // Removes a player, passing the host on to the next player if needed.
+    fn take_player(&mut self, possession: usize) -> Option<Player> {
+        let index = self
+            .game
+            .players
+            .iter()
+            .position(|p| p.possession == possession)?;
+        let player = self.game.players.remove(index);
+        if player.host {
+            if let Some(next) = self.game.players.iter_mut().min_by_key(|p| p.possession) {
+                next.host = true;
+                next.ready = false;
+            }
+        }
+        Some(player)
+    }
+
+    /// Gives a held seat back to the player who lost their connection, as it
+    /// was when they left. Returns None if the seat has been given up.
+    pub fn reconnect_player(&mut self, possession: usize) -> Option<Player> {
+        let player = self
+            .game
+            .players
+            .iter_mut()
+            .find(|player| player.possession == possession)?;
+        player.disconnected = false;
+        player.bot = false;
+        let player = player.clone();
+        self.event_source
+            .emit_event(GameEvent::PlayersChanged, &mut self.game);
+        Some(player)
+    }
+
+    /// Holds a player's seat after they lose their connection, until they
+    /// rejoin or `release_seat` is called.
+    pub fn disconnect_player(&mut self, possession: usize) {
+        let player = self
+            .game
+            .players
+            .iter_mut()
+            .find(|player| player.possession == possession);
+        if let Some(player) = player {
+            player.disconnected = true;
+            self.event_source
+                .emit_event(GameEvent::PlayersChanged, &mut self.game);
+        }
+    }
+
+    /// Takes a player out of a running game, handing their planets to the
+    /// heir or leaving them neutral.
+    pub fn surrender(
+        &mut self,
+        possession: usize,
+        heir: Option<usize>,
+    ) -> Result<Standing, SimulationError> {
+        if self.status() != GameStatus::Running {
+            return Err(SimulationError::Rejected(
+                "Only running games can be surrendered.".to_owned(),
+            ));
+        }
+        if heir == Some(possession) {
+            return Err(SimulationError::Rejected(
+                "Players cannot leave their planets to themselves.".to_owned(),
+            ));
+        }
+        if let Some(heir) = heir {
+            if !self.has_possession(heir) {
+                return Err(SimulationError::Rejected(format!(
+                    "There is no player {} in this game.",
+                    heir
+                )));
+            }
+        }
+        if !self.has_possession(possession) {
+            return Err(SimulationError::Rejected(format!(
+                "There is no player {} in this game.",
+                possession
+            )));
+        }
+        // Bring the galaxy up to date first, so nothing is lost in the handover
+        let time = self.current_tick();
+        self.step_to(time)?;
+        let player = self.take_player(possession).unwrap();
+        self.transfer_planets(possession, heir);
+        let standing = self.eliminate(&player);
+        self.record_winner();
+        self.event_source
+            .emit_event(GameEvent::Forfeit(player), &mut self.game);
+        Ok(standing)
+    }
+
+    /// Hands every planet and fleet in flight of one possession to another.
+    /// Without an heir the planets become neutral and the fleets are dropped,
+    /// since neutral planets don't attack. The galaxy should be stepped to
+    /// the current time first.
+    fn transfer_planets(&mut self, from: usize, to: Option<usize>) {
+        let galaxy = match self.game.state.as_mut() {
+            Some(galaxy) => galaxy,
+            None => return,
+        };
+        for planet in galaxy.planets.iter_mut() {
+            if planet.possession == Some(from) {
+                planet.possession = to;
+            }
+        }
+        let time = galaxy.time;
+        let in_flight = |game_move: &Move| {
+            game_move.from.possession == Some(from) && game_move.end_time() > time
+        };
+        match to {
+            Some(heir) => {
+                for game_move in galaxy.moves.iter_mut().filter(|m| in_flight(m)) {
+                    game_move.from.possession = Some(heir);
+                }
+            }
+            None => {
+                let dropped = galaxy.moves[..self.completed_move_idx]
+                    .iter()
+                    .filter(|game_move| in_flight(game_move))
+                    .count();
+                galaxy.moves.retain(|game_move| !in_flight(game_move));
+                self.completed_move_idx -= dropped;
+            }
+        }
+        // Ships that haven't landed yet are waiting in the buckets
+        for bucket in self.modification_buckets.iter_mut().flatten() {
+            for deltas in bucket.deltas_by_planet.iter_mut() {
+                let ships: u32 = deltas
+                    .iter()
+                    .filter(|delta| delta.possession == from as u32)
+                    .map(|delta| delta.magnitude)
+                    .sum();
+                deltas.retain(|delta| delta.possession != from as u32);
+                let heir = match to {
+                    Some(heir) if ships > 0 => heir as u32,
+                    _ => continue,
+                };
+                match deltas.iter_mut().find(|delta| delta.possession == heir) {
+                    Some(delta) => delta.magnitude += ships,
+                    None => deltas.push(PlanetDelta {
+                        possession: heir,
+                        magnitude: ships,
+                    }),
+                }
+            }
+        }
+    }
+
+    fn standing(&self, possession: usize) -> Option<&Standing> {
+        self.game
+            .standings
+            .iter()
+            .find(|standing| standing.possession == possession)
+    }
+
+    /// Records the placement of a player who is out of the game, behind
+    /// everyone still playing.
+    fn eliminate(&mut self, player: &Player) -> Standing {
+        if let Some(standing) = self.standing(player.possession) {
+            return standing.clone();
+        }
+        let playing = self
+            .game
+            .players
+            .iter()
+            .filter(|other| other.possession != player.possession)
+            .filter(|other| self.standing(other.possession).is_none())
+            .count();
+        let standing = Standing {
+            possession: player.possession,
+            name: player.name.clone(),
+            placement: playing as u32 + 1,
+        };
+        self.game.standings.push(standing.clone());
+        log!(
+            debug,
+            "{} was eliminated in place {}",
+            standing.name,
+            standing.placement
+        );
+        self.event_source
+            .emit_event(GameEvent::Eliminated(standing.clone()), &mut self.game);
+        standing
+    }
+
+    /// Records the winner's standing once the game is over.
+    pub fn record_winner(&mut self) {
+        let winner = match self.game.state.as_ref().and_then(|galaxy| galaxy.winner()) {
+            Some(winner) => winner,
+            None => return,
+        };
+        if self
+            .game
+            .standings
+            .iter()
+            .any(|standing| standing.placement == 1)
+        {
+            return;
+        }
+        let name = self
+            .game
+            .players
+            .iter()
+            .find(|player| player.possession == winner)
+            .map_or_else(String::new, |player| player.name.clone());
+        self.game.standings.push(Standing {
+            possession: winner,
+            name,
+            placement: 1,
+        });
+    }
+
+    /// Eliminates players who have no planets or ships left. They keep their
+    /// seats, and watch the rest of the game.
+    fn eliminate_defeated(&mut self) {
+        let galaxy = match &self.game.state {
+            Some(galaxy) => galaxy,
+            None => return,
+        };
+        let defeated: Vec<Player> = self
+            .game
+            .players
+            .iter()
+            .filter(|player| self.standing(player.possession).is_none())
+            .filter(|player| {
+                let possession = Some(player.possession);
+                let holds_planets = galaxy
+                    .planets
+                    .iter()
+                    .any(|planet| planet.possession == possession);
+                let has_ships = galaxy.moves.iter().any(|game_move| {
+                    game_move.from.possession == possession && game_move.end_time() > galaxy.time
+                });
+                !holds_planets && !has_ships
+            })
+            .cloned()
+            .collect();
+        if defeated.is_empty() {
+            return;
+        }
+        for player in defeated {
+            self.eliminate(&player);
+        }
+        self.record_winner();
+    }
+
+    /// The state of the planets, if the game has started.
+    pub fn heartbeat(&self) -> Option<Heartbeat> {
+        let galaxy = self.game.state.as_ref()?;
+        Some(Heartbeat {
+            time: galaxy.time,
+            planets: galaxy
+                .planets
+                .iter()
+                .map(|planet| PlanetStatus {
+                    possession: planet.possession,
+                    value: planet.value,
+                })
+                .collect(),
+        })
+    }
+
+    /// Gives up the seat of a player who is disconnected or idle, as the
+    /// game's disconnect policy says. Returns whether the player left the game.
+    pub fn release_seat(&mut self, possession: usize) -> Result<bool, SimulationError> {
+        if self.game.config.disconnect_policy == DisconnectPolicy::Bot {
+            let player = self
+                .game
+                .players
+                .iter_mut()
+                .find(|player| player.possession == possession);
+            if let Some(player) = player {
+                player.disconnected = false;
+                player.bot = true;
+                self.event_source
+                    .emit_event(GameEvent::PlayersChanged, &mut self.game);
+            }
+            return Ok(false);
+        }
+        if !self.has_possession(possession) {
+            return Ok(false);
+        }
+        let neutral = self.game.config.disconnect_policy == DisconnectPolicy::Neutral;
+        if neutral && self.game.state.is_some() {
+            let time = self.current_tick();
+            self.step_to(time)?;
+        }
+        // Checked up front, since handing over the planets can end the game
+        let running = self.status() == GameStatus::Running;
+        let player = self.take_player(possession).unwrap();
+        if neutral {
+            self.transfer_planets(possession, None);
+        }
+        if running {
+            self.eliminate(&player);
+            self.record_winner();
+        }
+        self.event_source
+            .emit_event(GameEvent::Forfeit(player), &mut self.game);
+        Ok(true)
+    }
+
+    /// Gives a player who a bot took over for control back.
+    pub fn resume_control(&mut self, possession: usize) {
+        let player = self
+            .game
+            .players
+            .iter_mut()
+            .find(|player| player.possession == possession && player.bot);
+        if let Some(player) = player {
+            player.bot = false;
+            self.event_source
+                .emit_event(GameEvent::PlayersChanged, &mut self.game);
+        }
+    }
+
+    /// Whether anyone other than bots is still playing.
+    pub fn has_human_players(&self) -> bool {
+        self.game.players.iter().any(|player| !player.bot)
+    }
+
+    /// The possession of the game's host, if anyone has joined.
+    pub fn host(&self) -> Option<usize> {
+        self.game
+            .players
+            .iter()
+            .find(|player| player.host)
+            .map(|player| player.possession)
+    }
+
+    /// Makes another player the host.
+    pub fn set_host(&mut self, possession: usize) -> Result<(), String> {
+        if !self.has_possession(possession) {
+            return Err(format!("There is no player {} in this game.", possession));
+        }
+        for player in self.game.players.iter_mut() {
+            player.host = player.possession == possession;
+            if player.host {
+                player.ready = false;
+            }
+        }
+        self.event_source
+            .emit_event(GameEvent::PlayersChanged, &mut self.game);
+        Ok(())
+    }
+
+    pub fn set_ready(&mut self, possession: usize, ready: bool) -> Result<(), String> {
+        if self.game.state.is_some() {
+            return Err("The game has already started.".to_owned());
+        }
+        let player = self
+            .game
+            .players
+            .iter_mut()
+            .find(|player| player.possession == possession)
+            .ok_or_else(|| format!("There is no player {} in this game.", possession))?;
+        player.ready = ready;
+        self.event_source
+            .emit_event(GameEvent::PlayersChanged, &mut self.game);
+        Ok(())
+    }
+
+    /// Most players the game's map supports.
+    pub fn capacity(&self) -> usize {
+        self.game
+            .map
+            .planets
+            .first()
+            .map_or(0, |planet| planet.possession.len())
+    }
+
+    pub fn status(&self) -> GameStatus {
+        match &self.game.state {
+            None => GameStatus::Waiting,
+            Some(galaxy) if galaxy.winner().is_some() => GameStatus::Finished,
+            Some(_) => GameStatus::Running,
+        }
+    }
+
+    /// Whether every player other than the host is ready to start.
+    pub fn all_ready(&self) -> bool {
+        self.game
+            .players
+            .iter()
+            .all(|player| player.host || player.ready)
+    }
+
+    /// Changes the map and configuration of a game that has not started.
+    /// Everyone has to ready up again afterwards.
+    pub fn update_settings(&mut self, map: map::Map, config: GameConfig) -> Result<(), String> {
+        if self.game.state.is_some() {
+            return Err("The settings cannot be changed once the game has started.".to_owned());
+        }
+        let max_players = map
+            .planets
+            .first()
+            .map_or(0, |planet| planet.possession.len());
+        if self.game.players.len() > max_players {
+            return Err(format!(
+                "The map \"{}\" only supports {} players.",
+                map.name, max_players
+            ));
+        }
+        self.game.map = map;
+        self.game.config = config;
+        for player in self.game.players.iter_mut() {
+            player.ready = false;
+        }
+        self.event_source
+            .emit_event(GameEvent::SettingsChanged, &mut self.game);
+        Ok(())
+    }
+
+    /// Sends a chat message to everyone in the game.
+    pub fn post_chat(&mut self, message: ChatMessage) {
+        self.event_source
+            .emit_event(GameEvent::Chat(message), &mut self.game);
+    }
+
+    pub fn start_game(&mut self) -> Result<(), SimulationError> {
+        let rejected = |reason: &str| Err(SimulationError::Rejected(reason.to_owned()));
+        if self.game.state.is_some() {
+            rejected("Cannot start game, it has already started.")
         } else if (self.game.players.len() as u32) < self.game.config.min_players {
-            Err("Cannot start game, insuffcient players".to_owned())
+            rejected("Cannot start game, insuffcient players")
+        } else if !self.all_ready() {
+            rejected("Cannot start game, not every player is ready.")
         } else {
             self.game.state = Some(self.game.map.to_galaxy(&mut self.game.players)?);
             self.start_time = get_millis();
+            log!(
+                debug,
+                "Started on {} with {} players",
+                self.game.map.name,
+                self.game.players.len()
+            );
             self.event_source
-                .emit_event(GameEvent::Start, &mut self.game);
+                .emit_event(GameEvent::Start(self.clock()), &mut self.game);
             Ok(())
         }
     }
@@ -263,11 +921,23 @@ impl GameExecutor {
     }
 
     #[inline(never)]
-    fn apply_move_from(time: &mut u32, planets: &mut Vec<Planet>, game_move: &Move) {
+    fn apply_move_from(
+        time: &mut u32,
+        planets: &mut [Planet],
+        game_move: &Move,
+    ) -> Result<(), SimulationError> {
+        // Moves should only be processed on a game state that matches the move time
         if *time != game_move.start_time {
-            panic!("Moves should only be processed on a game state that matches the move time.");
+            return Err(SimulationError::MoveOutOfOrder {
+                move_time: game_move.start_time,
+                galaxy_time: *time,
+            });
         }
-        planets[game_move.from.index].value -= game_move.armada_size as f32;
+        let planet = planets
+            .get_mut(game_move.from.index)
+            .ok_or(SimulationError::UnknownPlanet(game_move.from.index))?;
+        planet.value -= game_move.armada_size as f32;
+        Ok(())
     }
 
     #[inline(never)]
@@ -275,7 +945,14 @@ impl GameExecutor {
         planets: &mut Vec<Planet>,
         mod_buckets: &mut ModBuckets,
         game_move: &Move,
-    ) {
+    ) -> Result<(), SimulationError> {
+        if game_move.to.index >= planets.len() {
+            return Err(SimulationError::UnknownPlanet(game_move.to.index));
+        }
+        let attacker_player_index = game_move
+            .from
+            .possession
+            .ok_or(SimulationError::NeutralMove)? as u32;
         // This function can run before the move is even processed
         let first_time = game_move.first_arrival_time();
         // Bucket index is offset from the oldest bucket
@@ -298,18 +975,16 @@ impl GameExecutor {
             .sqrt()
                 - game_move.to.radius;
             // Time of arrival
-            let arrival = (dist / SHIP_SPEED) as u32 + game_move.start_time;
+            let arrival = ((dist / SHIP_SPEED) as u32).saturating_add(game_move.start_time);
             if first_bucket_time > arrival {
-                panic!("dist: {}, move.time: {}, first_time: {}, first_bucket_time: {}, arrival: {}, ship_pos.x: {}, ship_pos.y: {}",dist,game_move.start_time,first_time,first_bucket_time,arrival,ship_pos.0,ship_pos.1);
+                return Err(SimulationError::EarlyArrival {
+                    arrival,
+                    first_bucket_time,
+                });
             }
             let bucket_idx = (arrival - first_bucket_time) as usize;
             let cap = ((arrival - first_bucket_time) as usize + 1).max(mod_buckets.len());
             mod_buckets.resize(cap, None);
-            let attacker_player_index = game_move
-                .from
-                .possession
-                .expect("neutral player cannot make moves.")
-                as u32;
             if let Some(ref mut bucket) = mod_buckets[bucket_idx] {
                 match bucket.deltas_by_planet[game_move.to.index]
                     .iter_mut()
@@ -341,6 +1016,7 @@ impl GameExecutor {
                 });
             };
         }
+        Ok(())
     }
 
     fn apply_buckets(
@@ -348,7 +1024,8 @@ impl GameExecutor {
         planets: &mut Vec<Planet>,
         mod_buckets: &mut ModBuckets,
         target_time: u32,
-    ) {
+        captures: &mut Vec<Capture>,
+    ) -> Result<(), SimulationError> {
         let mut prev_time = *time;
         // Remove buckets that have already been accounted for by the current state.
         while mod_buckets
@@ -366,7 +1043,12 @@ impl GameExecutor {
                 .unwrap_or(true)
         {
             if let Some(bucket) = mod_buckets.pop_front().and_then(std::convert::identity) {
-                assert!(!(bucket.time < prev_time),"Late bucket application occured: bucket.time: {}, prev_time: {} target_time: {}",bucket.time,prev_time,target_time);
+                if bucket.time < prev_time {
+                    return Err(SimulationError::LateBucket {
+                        bucket_time: bucket.time,
+                        galaxy_time: prev_time,
+                    });
+                }
                 // Spawn ships up until the moment of bucket application
                 GameExecutor::spawn_ships(planets, bucket.time - prev_time);
                 prev_time = bucket.time;
@@ -375,6 +1057,12 @@ impl GameExecutor {
                         if Some(attacker.possession as usize) != planet.possession {
                             planet.value -= attacker.magnitude as f32;
                             if planet.value < 0.0 {
+                                captures.push(Capture {
+                                    time: bucket.time,
+                                    planet: i,
+                                    from: planet.possession,
+                                    to: attacker.possession as usize,
+                                });
                                 planet.possession = Some(attacker.possession as usize);
                                 planet.value = planet.value.abs();
                             }
@@ -386,21 +1074,29 @@ impl GameExecutor {
             }
         }
         if target_time < prev_time {
-            panic!("target: {}, prev: {}", target_time, prev_time);
+            return Err(SimulationError::TimeTravel {
+                target_time,
+                galaxy_time: prev_time,
+            });
         };
         GameExecutor::spawn_ships(planets, target_time - prev_time);
+        Ok(())
     }
 
-    pub fn step_to(&mut self, target_time: u32) {
+    /// Advances the galaxy to `target_time`, emitting an event for every
+    /// fleet that lands and planet that is captured along the way. Does
+    /// nothing before the game starts.
+    pub fn step_to(&mut self, target_time: u32) -> Result<(), SimulationError> {
+        let mut captures = Vec::new();
+        let mut arrivals = Vec::new();
         if let Some(ref mut galaxy) = self.game.state {
-            assert!(
-                !(galaxy.time > target_time),
-                "Cannot step into the past! Target time: {} is behind galaxy time: {}",
-                galaxy.time,
-                target_time
-            );
+            if galaxy.time > target_time {
+                return Err(SimulationError::TimeTravel {
+                    target_time,
+                    galaxy_time: galaxy.time,
+                });
+            }
             let prev_time = galaxy.time;
-            println!("{}", galaxy.time);
             let new_moves = galaxy
                 .moves
                 .iter()
@@ -416,7 +1112,8 @@ impl GameExecutor {
                         &mut galaxy.planets,
                         &mut self.modification_buckets,
                         game_move.start_time,
-                    );
+                        &mut captures,
+                    )?;
                     galaxy.time = game_move.start_time;
 
                     if prev_time < galaxy.time {
@@ -424,14 +1121,14 @@ impl GameExecutor {
                             &mut galaxy.time,
                             &mut galaxy.planets,
                             game_move,
-                        );
+                        )?;
                     }
                 }
                 GameExecutor::apply_move_mod_buckets(
                     &mut galaxy.planets,
                     &mut self.modification_buckets,
                     game_move,
-                );
+                )?;
                 self.completed_move_idx += 1;
             }
             if galaxy.time < target_time {
@@ -440,49 +1137,123 @@ impl GameExecutor {
                     &mut galaxy.planets,
                     &mut self.modification_buckets,
                     target_time,
-                );
+                    &mut captures,
+                )?;
             };
             galaxy.time = target_time;
+            log!(trace, "Stepped to {}", target_time);
+            arrivals = galaxy
+                .moves
+                .iter()
+                .filter(|game_move| {
+                    let end_time = game_move.end_time();
+                    prev_time < end_time && end_time <= target_time
+                })
+                .map(|game_move| Arrival {
+                    time: game_move.end_time(),
+                    from: game_move.from.index,
+                    to: game_move.to.index,
+                    possession: game_move.from.possession,
+                    armada_size: game_move.armada_size,
+                })
+                .collect();
         }
+        if captures.is_empty() && arrivals.is_empty() {
+            return Ok(());
+        }
+        let mut events: Vec<(u32, GameEvent)> = captures
+            .into_iter()
+            .map(|capture| (capture.time, GameEvent::Capture(capture)))
+            .chain(
+                arrivals
+                    .into_iter()
+                    .map(|arrival| (arrival.time, GameEvent::Arrival(arrival))),
+            )
+            .collect();
+        events.sort_by_key(|(time, _)| *time);
+        for (_, event) in events {
+            self.event_source.emit_event(event, &mut self.game);
+        }
+        self.eliminate_defeated();
+        Ok(())
+    }
+
+    pub fn create_move(&mut self, from: u16, to: u16) -> Result<Move, SimulationError> {
+        let time = self.current_tick();
+        self.create_move_at(from, to, time)
     }
 
-    pub fn create_move(&mut self, from: u16, to: u16) -> Result<Move, String> {
-        let time = self.get_time();
-        self.step_to(time);
+    /// Creates a move starting at the given tick, which can't be before the galaxy's time.
+    pub fn create_move_at(
+        &mut self,
+        from: u16,
+        to: u16,
+        time: u32,
+    ) -> Result<Move, SimulationError> {
+        self.step_to(time)?;
         let galaxy = self
             .game
             .state
-            .as_mut()
-            .ok_or_else(|| "Game has not been started.".to_owned())?;
+            .as_ref()
+            .ok_or(SimulationError::NotStarted)?;
+        let planet = |index: u16| {
+            galaxy
+                .planets
+                .get(index as usize)
+                .ok_or(SimulationError::UnknownPlanet(index as usize))
+        };
+        let from = planet(from)?;
         Ok(Move {
-            to: galaxy.planets[to as usize].clone(),
-            from: galaxy.planets[from as usize].clone(),
-            armada_size: galaxy.planets[from as usize].value as u32 / 2,
+            to: planet(to)?.clone(),
+            from: from.clone(),
+            armada_size: from.value as u32 / 2,
             start_time: time,
         })
     }
 
     pub fn get_time(&self) -> u32 {
-        ((get_millis() - self.start_time) / 17) as u32
+        (get_millis().saturating_sub(self.start_time) / self.tick_length as u128) as u32
     }
 
-    pub fn add_move(&mut self, player: &Player, game_move: Move) -> Result<(), String> {
-        self.step_to(game_move.start_time);
+    /// The current tick, which is never behind the galaxy even if the
+    /// system clock is set back.
+    pub fn current_tick(&self) -> u32 {
+        let galaxy_time = self.game.state.as_ref().map_or(0, |galaxy| galaxy.time);
+        self.get_time().max(galaxy_time)
+    }
+
+    pub fn clock(&self) -> GameClock {
+        GameClock {
+            start_time: self.start_time,
+            tick_length: self.tick_length,
+        }
+    }
+
+    pub fn add_move(&mut self, player: &Player, game_move: Move) -> Result<(), SimulationError> {
+        self.step_to(game_move.start_time)?;
         let galaxy = self
             .game
             .state
             .as_mut()
-            .ok_or_else(|| "Game has not been started.".to_owned())?;
-        if galaxy.planets[game_move.from.index]
-            .possession
-            .map_or(false, |idx| player.possession != idx)
-        {
-            Err("Planet not owned by player.".to_owned())
+            .ok_or(SimulationError::NotStarted)?;
+        let from = galaxy
+            .planets
+            .get(game_move.from.index)
+            .ok_or(SimulationError::UnknownPlanet(game_move.from.index))?;
+        if game_move.to.index >= galaxy.planets.len() {
+            Err(SimulationError::UnknownPlanet(game_move.to.index))
+        } else if from.possession != Some(player.possession) {
+            Err(SimulationError::Rejected(
+                "Planet not owned by player.".to_owned(),
+            ))
         } else if game_move.from.index == game_move.to.index {
-            Err("Planet cannot move to itself.".to_owned())
+            Err(SimulationError::Rejected(
+                "Planet cannot move to itself.".to_owned(),
+            ))
         } else {
-            GameExecutor::apply_move_from(&mut galaxy.time, &mut galaxy.planets, &game_move);
+            GameExecutor::apply_move_from(&mut galaxy.time, &mut galaxy.planets, &game_move)?;
             galaxy.moves.push(game_move.clone());
+            self.record_winner();
             self.event_source
                 .emit_event(GameEvent::Move(game_move), &mut self.game);
             Ok(())
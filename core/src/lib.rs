@@ -1,3 +1,7 @@
+// Enum defaults are written out by hand, since deriving them needs
+// `#[default]`, which the pinned toolchain doesn't support
+#![allow(clippy::derivable_impls)]
+
 cfg_if! {
     if #[cfg(target_arch = "wasm32")] {
         use wasm_bindgen::prelude::*;
@@ -11,6 +15,22 @@ extern crate serde_derive;
 extern crate cfg_if;
 //#[macro_use]
 //extern crate static_assertions;
+
+/// Logs through `tracing` when the `logging` feature is enabled, and does
+/// nothing otherwise, so the wasm client doesn't print from the simulation.
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "logging")]
+        tracing::$level!($($arg)+);
+        #[cfg(not(feature = "logging"))]
+        {
+            if false {
+                let _ = format_args!($($arg)+);
+            }
+        }
+    };
+}
+
 //#[macro_use]
 pub mod game;
 pub mod protocol;
@@ -0,0 +1,72 @@
+use crate::game::GameClock;
+use crate::protocol::messages::TimeSync;
+
+/// Number of request/reply rounds used to estimate the server clock.
+pub const SYNC_ROUNDS: usize = 8;
+
+/// A single completed clock synchronisation round.
+#[derive(Clone, Copy)]
+struct Sample {
+    /// Estimated difference between the server clock and ours (server - client)
+    offset: f64,
+    /// Time the request spent on the network, excluding server processing
+    round_trip: f64,
+}
+
+/// Estimates the server clock from a series of NTP-style exchanges.
+///
+/// Each round records four timestamps: the client send time (t0), the
+/// server receive time (t1), the server send time (t2) and the client
+/// receive time (t3). From those, `offset = ((t1 - t0) + (t2 - t3)) / 2`
+/// and `round_trip = (t3 - t0) - (t2 - t1)`. The sample with the shortest
+/// round trip is the least affected by asymmetric network delay, so that
+/// is the one used.
+#[derive(Default)]
+pub struct ClockSync {
+    samples: Vec<Sample>,
+}
+
+impl ClockSync {
+    /// Discards previous measurements, so a fresh synchronisation can begin.
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Records a server reply received at `receive_time` (client clock).
+    /// Returns true while more rounds are needed.
+    pub fn add_reply(&mut self, reply: &TimeSync, receive_time: u128) -> bool {
+        let t0 = reply.client_send_time as f64;
+        let t1 = reply.server_receive_time as f64;
+        let t2 = reply.server_send_time as f64;
+        let t3 = receive_time as f64;
+        self.samples.push(Sample {
+            offset: ((t1 - t0) + (t2 - t3)) / 2.0,
+            round_trip: ((t3 - t0) - (t2 - t1)).max(0.0),
+        });
+        self.samples.len() < SYNC_ROUNDS
+    }
+
+    fn best_sample(&self) -> Option<Sample> {
+        self.samples
+            .iter()
+            .copied()
+            .min_by(|a, b| a.round_trip.partial_cmp(&b.round_trip).unwrap())
+    }
+
+    /// Milliseconds to add to the client clock to get the server clock.
+    pub fn offset(&self) -> Option<f64> {
+        self.best_sample().map(|sample| sample.offset)
+    }
+
+    /// Round trip latency to the server in milliseconds.
+    pub fn round_trip(&self) -> Option<f64> {
+        self.best_sample().map(|sample| sample.round_trip)
+    }
+
+    /// Converts a client clock reading into a game tick, using the server's clock for the game.
+    pub fn game_tick(&self, clock: &GameClock, client_time: u128) -> Option<u32> {
+        let server_time = client_time as f64 + self.offset()?;
+        let elapsed = (server_time - clock.start_time as f64).max(0.0);
+        Some((elapsed / clock.tick_length as f64) as u32)
+    }
+}
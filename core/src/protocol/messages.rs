@@ -1,4 +1,8 @@
-use crate::game::{map::Map, Galaxy, Game, GameConfig, Move, Player};
+use crate::game::{
+    map::Map, Arrival, Capture, Galaxy, Game, GameClock, GameConfig, Heartbeat, Move, Player,
+    Standing,
+};
+use schemars::JsonSchema;
 use std::collections::HashMap;
 
 cfg_if! {
@@ -7,61 +11,222 @@ cfg_if! {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct SetName {
     pub name: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct GameState {
     pub galaxy: Galaxy,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct GameMove {
     pub to: u16,
     pub from: u16,
 }
 
-#[derive(Deserialize, Serialize)]
+/// Who can see and join a game.
+#[derive(Deserialize, Serialize, Clone, PartialEq, JsonSchema)]
+pub enum Visibility {
+    /// Listed in the lobby, anyone can join
+    Public,
+    /// Hidden from the lobby, joined with an invite code
+    Unlisted,
+    /// Listed in the lobby, joined with the password or an invite code
+    Password(String),
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Public
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct CreateGame {
     pub map_id: String,
     pub config: GameConfig,
+    #[serde(default)]
+    pub visibility: Visibility,
 }
 
 //#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
 pub struct GameMetadata {
     pub game_id: String,
     pub config: GameConfig,
     pub map_id: String,
+    /// Whether a password is needed to join
+    #[serde(default)]
+    pub password_protected: bool,
+    #[serde(default)]
+    pub spectators: u32,
+    #[serde(default)]
+    pub players: u32,
+    /// Most players the map supports
+    #[serde(default)]
+    pub capacity: u32,
+    #[serde(default)]
+    pub status: GameStatus,
+    /// Server time the game was created, in milliseconds since the unix epoch
+    #[serde(default)]
+    pub created_at: u128,
+    /// Name of the host, once someone has joined
+    #[serde(default)]
+    pub host: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum GameStatus {
+    Waiting,
+    Running,
+    Finished,
+}
+
+impl Default for GameStatus {
+    fn default() -> Self {
+        GameStatus::Waiting
+    }
+}
+
+/// Asks for a page of the listed games matching some filters, newest first.
+/// The server replies with a `GameList`.
+#[derive(Deserialize, Serialize, Clone, Default, JsonSchema)]
+pub struct GameQuery {
+    #[serde(default)]
+    pub map_id: Option<String>,
+    #[serde(default)]
+    pub status: Option<GameStatus>,
+    /// Only games that are waiting with room for more players
+    #[serde(default)]
+    pub open_slots: bool,
+    #[serde(default)]
+    pub offset: u32,
+    /// Defaults to (and is capped at) the server's page size
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+impl GameQuery {
+    pub fn matches(&self, game: &GameMetadata) -> bool {
+        self.map_id.iter().all(|map_id| map_id == &game.map_id)
+            && self.status.iter().all(|status| *status == game.status)
+            && (!self.open_slots
+                || (game.status == GameStatus::Waiting && game.players < game.capacity))
+    }
+}
+
+/// Sent to the creator of a game, with the code that lets others join it.
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub struct GameCreated {
+    pub game: GameMetadata,
+    pub invite_code: InviteCode,
+}
+
+/// Gives up the game. The player's planets go to the heir, a player given by
+/// possession, or become neutral. The player stays on as a spectator.
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub struct Surrender {
+    pub heir: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct GameList {
     pub games: Vec<GameMetadata>,
+    /// Number of games matching the query, of which `games` is a page
+    #[serde(default)]
+    pub total: u32,
+    #[serde(default)]
+    pub offset: u32,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct GamePlayers {
     pub game_id: String,
     pub players: Vec<PlayerMetadata>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct PlayerMetadata {
     name: String,
 }
 
+/// One round of the clock synchronisation exchange.
+///
+/// The client sends its own clock reading and the server echoes it back
+/// along with the times it received the request and sent the reply, which
+/// lets the client estimate both its offset from the server clock and the
+/// round trip latency. All times are milliseconds since the unix epoch.
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub struct TimeSync {
+    pub client_send_time: u128,
+    #[serde(default)]
+    pub server_receive_time: u128,
+    #[serde(default)]
+    pub server_send_time: u128,
+}
+
+impl TimeSync {
+    /// Creates the client's half of an exchange, sent at `client_send_time`.
+    pub fn request(client_send_time: u128) -> TimeSync {
+        TimeSync {
+            client_send_time,
+            server_receive_time: 0,
+            server_send_time: 0,
+        }
+    }
+}
+
+/// Who a chat message is addressed to.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum ChatScope {
+    /// Everyone connected to the server
+    Lobby,
+    /// Everyone in the sender's current game
+    Game,
+}
+
+/// A chat message a client wants to send.
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub struct SendChat {
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+/// A chat message as delivered to clients.
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub struct ChatMessage {
+    pub scope: ChatScope,
+    /// Name of the player who sent the message
+    pub sender: String,
+    pub text: String,
+    /// Server time the message was sent, in milliseconds since the unix epoch
+    pub time: u128,
+}
+
 type GameID = String;
+/// Session token a player uses to take back their seat after disconnecting
 pub type RejoinCode = String;
-#[derive(Deserialize, Serialize)]
+pub type InviteCode = String;
+pub type RequestId = u32;
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct EnterGame {
     pub game_id: GameID,
     pub rejoin_code: Option<RejoinCode>,
+    /// Needed for password protected games, unless an invite code is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Needed for unlisted games
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite_code: Option<InviteCode>,
+    /// Watch the game without taking part in it
+    #[serde(default)]
+    pub spectate: bool,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub enum MessageType {
     SetName(SetName),
     EnterGame(EnterGame),
@@ -81,5 +246,115 @@ pub enum MessageType {
     MapList(HashMap<String, Map>),
     CreateGame(CreateGame),
     Error(String),
-    Time(u128),
+    Time(TimeSync),
+    GameClock(GameClock),
+    Ack,
+    SendChat(SendChat),
+    Chat(ChatMessage),
+    /// Recent messages of a chat, sent to clients as they join it
+    ChatHistory(Vec<ChatMessage>),
+    /// Marks the player as ready (or not) to start the game
+    SetReady(bool),
+    /// Host only: removes the player with the given possession
+    KickPlayer(u32),
+    /// Host only: hands hosting over to the player with the given possession
+    TransferHost(u32),
+    /// Host only: changes the map and configuration before the game starts.
+    /// A game's visibility cannot be changed.
+    UpdateGameSettings(CreateGame),
+    /// Sent to everyone when a game's settings change
+    UpdateGame(GameMetadata),
+    /// Sent to a player who was kicked from the game with the given id
+    Kicked(String),
+    GameCreated(GameCreated),
+    QueryGames(GameQuery),
+    Surrender(Surrender),
+    /// Sent to everyone in a game when a player is out of it
+    Eliminated(Standing),
+    /// Sent to everyone in a game when a fleet finishes landing
+    Arrival(Arrival),
+    /// Sent to everyone in a game when a planet changes hands
+    Capture(Capture),
+    /// Sent to everyone in a running game at a regular interval
+    Heartbeat(Heartbeat),
+    /// Asks for a fresh copy of the current game, for clients whose copy
+    /// ran into a `SimulationError`. Answered with `GameClock` and `Game`.
+    SyncGame,
+}
+
+impl MessageType {
+    /// Name of the message variant, used to describe requests in
+    /// acknowledgements and errors.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessageType::SetName(_) => "SetName",
+            MessageType::EnterGame(_) => "EnterGame",
+            MessageType::Possession(_) => "Possession",
+            MessageType::Game(_) => "Game",
+            MessageType::GameState(_) => "GameState",
+            MessageType::GameMove(_) => "GameMove",
+            MessageType::GamePlayers(_) => "GamePlayers",
+            MessageType::TimedGameMove(_) => "TimedGameMove",
+            MessageType::StartGame => "StartGame",
+            MessageType::ExitGame => "ExitGame",
+            MessageType::Ping => "Ping",
+            MessageType::Pong => "Pong",
+            MessageType::NewGame(_) => "NewGame",
+            MessageType::RemoveGame(_) => "RemoveGame",
+            MessageType::GameList(_) => "GameList",
+            MessageType::MapList(_) => "MapList",
+            MessageType::CreateGame(_) => "CreateGame",
+            MessageType::Error(_) => "Error",
+            MessageType::Time(_) => "Time",
+            MessageType::GameClock(_) => "GameClock",
+            MessageType::Ack => "Ack",
+            MessageType::SendChat(_) => "SendChat",
+            MessageType::Chat(_) => "Chat",
+            MessageType::ChatHistory(_) => "ChatHistory",
+            MessageType::SetReady(_) => "SetReady",
+            MessageType::KickPlayer(_) => "KickPlayer",
+            MessageType::TransferHost(_) => "TransferHost",
+            MessageType::UpdateGameSettings(_) => "UpdateGameSettings",
+            MessageType::UpdateGame(_) => "UpdateGame",
+            MessageType::Kicked(_) => "Kicked",
+            MessageType::GameCreated(_) => "GameCreated",
+            MessageType::QueryGames(_) => "QueryGames",
+            MessageType::Surrender(_) => "Surrender",
+            MessageType::Eliminated(_) => "Eliminated",
+            MessageType::Arrival(_) => "Arrival",
+            MessageType::Capture(_) => "Capture",
+            MessageType::Heartbeat(_) => "Heartbeat",
+            MessageType::SyncGame => "SyncGame",
+        }
+    }
+}
+
+/// Every message sent over the socket is wrapped in an envelope.
+///
+/// When a client sets `request_id`, the server answers the request with
+/// either an `Ack` or an `Error` carrying the same id, so the client can
+/// tell which of its requests failed.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<RequestId>,
+    pub message: MessageType,
+}
+
+impl Envelope {
+    pub fn reply(request_id: Option<RequestId>, message: MessageType) -> Envelope {
+        Envelope {
+            request_id,
+            message,
+        }
+    }
+}
+
+impl From<MessageType> for Envelope {
+    fn from(message: MessageType) -> Envelope {
+        Envelope {
+            request_id: None,
+            message,
+        }
+    }
 }
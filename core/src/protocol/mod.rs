@@ -1 +1,9 @@
+pub mod clock_sync;
 pub mod messages;
+
+/// Generates the JSON Schema of the wire protocol, covering every message
+/// (and every game type inside one) that can be sent over the socket.
+pub fn schema() -> String {
+    let schema = schemars::schema_for!(messages::Envelope);
+    serde_json::to_string_pretty(&schema).unwrap()
+}
@@ -0,0 +1,50 @@
+use ipg_core::game::{DisconnectPolicy, GameConfig};
+use ipg_core::protocol::messages::{GameMetadata, GameQuery, GameStatus};
+
+fn game(map_id: &str, status: GameStatus, players: u32) -> GameMetadata {
+    GameMetadata {
+        game_id: "game".to_owned(),
+        config: GameConfig {
+            min_players: 2,
+            disconnect_policy: DisconnectPolicy::Forfeit,
+        },
+        map_id: map_id.to_owned(),
+        password_protected: false,
+        spectators: 0,
+        players,
+        capacity: 3,
+        status,
+        created_at: 0,
+        host: None,
+    }
+}
+
+#[test]
+fn empty_queries_match_every_game() {
+    let query = GameQuery::default();
+    assert!(query.matches(&game("Trio", GameStatus::Waiting, 1)));
+    assert!(query.matches(&game("Warzone", GameStatus::Finished, 3)));
+}
+
+#[test]
+fn queries_filter_by_map_and_status() {
+    let query = GameQuery {
+        map_id: Some("Trio".to_owned()),
+        status: Some(GameStatus::Running),
+        ..GameQuery::default()
+    };
+    assert!(query.matches(&game("Trio", GameStatus::Running, 2)));
+    assert!(!query.matches(&game("Warzone", GameStatus::Running, 2)));
+    assert!(!query.matches(&game("Trio", GameStatus::Waiting, 2)));
+}
+
+#[test]
+fn open_slots_are_waiting_games_with_room() {
+    let query = GameQuery {
+        open_slots: true,
+        ..GameQuery::default()
+    };
+    assert!(query.matches(&game("Trio", GameStatus::Waiting, 2)));
+    assert!(!query.matches(&game("Trio", GameStatus::Waiting, 3)));
+    assert!(!query.matches(&game("Trio", GameStatus::Running, 2)));
+}
@@ -0,0 +1,14 @@
+use std::fs;
+use std::path::Path;
+
+/// Fails when the protocol types change without the committed schema being regenerated.
+#[test]
+fn committed_schema_is_up_to_date() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("schema/protocol.json");
+    let committed = fs::read_to_string(&path).expect("Unable to read the committed schema.");
+    assert!(
+        committed.replace("\r\n", "\n").trim_end() == ipg_core::protocol::schema(),
+        "{} is out of date. Regenerate it with `cargo run -p ipg_core --bin export_schema > core/schema/protocol.json`.",
+        path.display()
+    );
+}
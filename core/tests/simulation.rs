@@ -0,0 +1,200 @@
+//! Property tests feeding the simulation arbitrary input sequences. Bad
+//! input may be rejected, but must never panic.
+
+use ipg_core::game::map::{Map, MapSize, Planet as MapPlanet};
+use ipg_core::game::{DisconnectPolicy, Game, GameConfig, GameExecutor, Player, SimulationError};
+use proptest::prelude::*;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+enum Input {
+    /// Steps forward, or backward, by some ticks
+    Step {
+        ticks: u32,
+        backward: bool,
+    },
+    Move {
+        possession: usize,
+        from: u16,
+        to: u16,
+        /// Ticks after the galaxy's time the move is made at
+        delay: u32,
+    },
+    Surrender {
+        possession: usize,
+        heir: Option<usize>,
+    },
+    ReleaseSeat(usize),
+}
+
+fn load_map(name: &str) -> Map {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../maps")
+        .join(name);
+    Map::from_string(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// Starts a game on the map with the given number of players.
+fn start_game(map: Map, players: usize) -> GameExecutor {
+    let config = GameConfig {
+        min_players: 2,
+        disconnect_policy: DisconnectPolicy::Neutral,
+    };
+    let mut executor = GameExecutor::from_game(Game::new(map, config), "test".to_owned());
+    for index in 0..players {
+        let player = executor
+            .add_player(Player {
+                possession: index,
+                name: format!("Player {}", index),
+                ready: false,
+                host: false,
+                disconnected: false,
+                bot: false,
+            })
+            .unwrap();
+        executor.set_ready(player.possession, true).unwrap();
+    }
+    executor.start_game().unwrap();
+    executor
+}
+
+fn galaxy_time(executor: &GameExecutor) -> u32 {
+    executor.game.state.as_ref().unwrap().time
+}
+
+/// Applies the input, returning the error it ran into, if any.
+fn apply(executor: &mut GameExecutor, input: &Input) -> Result<(), SimulationError> {
+    let time = galaxy_time(executor);
+    match *input {
+        Input::Step { ticks, backward } => {
+            let target = if backward {
+                time.saturating_sub(ticks)
+            } else {
+                time + ticks
+            };
+            executor.step_to(target)
+        }
+        Input::Move {
+            possession,
+            from,
+            to,
+            delay,
+        } => {
+            let player = executor
+                .game
+                .players
+                .iter()
+                .find(|player| player.possession == possession)
+                .cloned();
+            let game_move = executor.create_move_at(from, to, time + delay)?;
+            match player {
+                Some(player) => executor.add_move(&player, game_move),
+                None => Ok(()),
+            }
+        }
+        Input::Surrender { possession, heir } => executor.surrender(possession, heir).map(|_| ()),
+        Input::ReleaseSeat(possession) => executor.release_seat(possession).map(|_| ()),
+    }
+}
+
+fn inputs(planets: u16, forward_only: bool) -> impl Strategy<Value = Vec<Input>> {
+    let step = (0..2_000u32, any::<bool>()).prop_map(move |(ticks, backward)| Input::Step {
+        ticks,
+        backward: backward && !forward_only,
+    });
+    let game_move =
+        (0..4usize, 0..planets, 0..planets, 0..500u32).prop_map(|(possession, from, to, delay)| {
+            Input::Move {
+                possession,
+                from,
+                to,
+                delay,
+            }
+        });
+    let surrender = (0..4usize, proptest::option::of(0..4usize))
+        .prop_map(|(possession, heir)| Input::Surrender { possession, heir });
+    let release_seat = (0..4usize).prop_map(Input::ReleaseSeat);
+    let input = prop_oneof![
+        4 => step,
+        8 => game_move,
+        1 => surrender,
+        1 => release_seat,
+    ];
+    proptest::collection::vec(input, 0..60)
+}
+
+/// Position, start value, radius, possessions and multiplier of a map planet
+type PlanetParts = (u32, u32, u32, u32, Vec<u32>, f32);
+
+fn map_planet() -> impl Strategy<Value = PlanetParts> {
+    (
+        0..2_000u32,
+        0..2_000u32,
+        0..200u32,
+        0..80u32,
+        proptest::collection::vec(0..6u32, 0..6),
+        0f32..4f32,
+    )
+}
+
+proptest! {
+    #[test]
+    fn no_input_sequence_panics(
+        map in prop_oneof![Just("trio.json"), Just("warzone.json")],
+        inputs in inputs(24, false),
+    ) {
+        let mut executor = start_game(load_map(map), 2);
+        for input in &inputs {
+            let _ = apply(&mut executor, input);
+        }
+    }
+
+    #[test]
+    fn valid_inputs_never_corrupt_the_galaxy(
+        map in prop_oneof![Just("trio.json"), Just("warzone.json")],
+        inputs in inputs(3, true),
+    ) {
+        let mut executor = start_game(load_map(map), 2);
+        for input in &inputs {
+            if let Err(error) = apply(&mut executor, input) {
+                prop_assert!(!error.is_internal(), "{:?} failed: {}", input, error);
+            }
+        }
+    }
+
+    #[test]
+    fn any_map_and_player_count_makes_a_galaxy_or_an_error(
+        planets in proptest::collection::vec(map_planet(), 0..12),
+        players in 0..8usize,
+    ) {
+        let map = Map {
+            size: MapSize { x: 2_000, y: 2_000 },
+            name: "Generated".to_owned(),
+            planets: planets
+                .into_iter()
+                .map(|(x, y, start_value, radius, possession, multiplier)| MapPlanet {
+                    x,
+                    y,
+                    start_value,
+                    radius,
+                    possession,
+                    multiplier,
+                })
+                .collect(),
+        };
+        let mut players: Vec<Player> = (0..players)
+            .map(|possession| Player {
+                possession,
+                name: format!("Player {}", possession),
+                ready: true,
+                host: false,
+                disconnected: false,
+                bot: false,
+            })
+            .collect();
+        if let Ok(galaxy) = map.to_galaxy(&mut players) {
+            prop_assert_eq!(galaxy.planets.len(), map.planets.len());
+        }
+    }
+}
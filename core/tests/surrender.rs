@@ -0,0 +1,155 @@
+//! Players surrendering a running game, handing their planets on or
+//! leaving them neutral.
+
+use ipg_core::game::map::Map;
+use ipg_core::game::{DisconnectPolicy, Game, GameConfig, GameEvent, GameExecutor, Player};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Possessions eliminated from a game and the places they finished in
+type Eliminated = Arc<Mutex<Vec<(usize, u32)>>>;
+
+/// Starts a three player game on Trio, along with the standings of the
+/// players eliminated from it.
+fn start_game() -> (GameExecutor, Eliminated) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../maps/trio.json");
+    let map = Map::from_string(&fs::read_to_string(path).unwrap()).unwrap();
+    let config = GameConfig {
+        min_players: 2,
+        disconnect_policy: DisconnectPolicy::Forfeit,
+    };
+    let mut executor = GameExecutor::from_game(Game::new(map, config), "test".to_owned());
+    for index in 0..3 {
+        let player = executor
+            .add_player(Player {
+                possession: index,
+                name: format!("Player {}", index),
+                ready: false,
+                host: false,
+                disconnected: false,
+                bot: false,
+            })
+            .unwrap();
+        executor.set_ready(player.possession, true).unwrap();
+    }
+    executor.start_game().unwrap();
+    let eliminated = Arc::new(Mutex::new(Vec::new()));
+    let events = eliminated.clone();
+    executor
+        .event_source
+        .on_event(Box::new(move |event: &GameEvent, _: &mut Game| {
+            if let GameEvent::Eliminated(standing) = event {
+                let standing = (standing.possession, standing.placement);
+                events.lock().unwrap().push(standing);
+            }
+        }));
+    (executor, eliminated)
+}
+
+fn planets_of(executor: &GameExecutor, possession: Option<usize>) -> usize {
+    let galaxy = executor.game.state.as_ref().unwrap();
+    galaxy
+        .planets
+        .iter()
+        .filter(|planet| planet.possession == possession)
+        .count()
+}
+
+#[test]
+fn planets_pass_to_the_heir() {
+    let (mut executor, eliminated) = start_game();
+    let neutral = planets_of(&executor, None);
+    let owned = planets_of(&executor, Some(0)) + planets_of(&executor, Some(1));
+    let standing = executor.surrender(0, Some(1)).unwrap();
+    assert_eq!(standing.placement, 3);
+    assert_eq!(planets_of(&executor, Some(0)), 0);
+    assert_eq!(planets_of(&executor, Some(1)), owned);
+    assert_eq!(planets_of(&executor, None), neutral);
+    assert!(executor
+        .game
+        .players
+        .iter()
+        .all(|player| player.possession != 0));
+    assert_eq!(*eliminated.lock().unwrap(), vec![(0, 3)]);
+}
+
+#[test]
+fn planets_go_neutral_without_an_heir() {
+    let (mut executor, eliminated) = start_game();
+    let neutral = planets_of(&executor, None) + planets_of(&executor, Some(2));
+    executor.surrender(2, None).unwrap();
+    assert_eq!(planets_of(&executor, Some(2)), 0);
+    assert_eq!(planets_of(&executor, None), neutral);
+    assert_eq!(*eliminated.lock().unwrap(), vec![(2, 3)]);
+}
+
+/// Sends a fleet from one of the possession's planets to the weakest planet
+/// someone else holds, returning the tick it lands on.
+fn launch_fleet(executor: &mut GameExecutor, possession: usize) -> (usize, u32) {
+    let galaxy = executor.game.state.as_ref().unwrap();
+    let from = galaxy
+        .planets
+        .iter()
+        .find(|planet| planet.possession == Some(possession))
+        .unwrap()
+        .index;
+    let to = galaxy
+        .planets
+        .iter()
+        .filter(|planet| planet.possession != Some(possession))
+        .min_by(|a, b| a.value.partial_cmp(&b.value).unwrap())
+        .unwrap()
+        .index;
+    let player = executor.game.players[possession].clone();
+    let game_move = executor.create_move_at(from as u16, to as u16, 0).unwrap();
+    let landing = game_move.end_time();
+    executor.add_move(&player, game_move).unwrap();
+    (to, landing)
+}
+
+fn fleets_of(executor: &GameExecutor, possession: usize) -> usize {
+    let galaxy = executor.game.state.as_ref().unwrap();
+    galaxy
+        .moves
+        .iter()
+        .filter(|game_move| game_move.from.possession == Some(possession))
+        .count()
+}
+
+#[test]
+fn fleets_in_flight_pass_to_the_heir() {
+    let (mut executor, _) = start_game();
+    let (target, landing) = launch_fleet(&mut executor, 0);
+    executor.surrender(0, Some(1)).unwrap();
+    assert_eq!(fleets_of(&executor, 0), 0);
+    assert_eq!(fleets_of(&executor, 1), 1);
+    executor.step_to(landing + 1).unwrap();
+    // The fleet lands for the heir, never for the player who surrendered
+    assert_eq!(planets_of(&executor, Some(0)), 0);
+    let galaxy = executor.game.state.as_ref().unwrap();
+    assert_eq!(galaxy.planets[target].possession, Some(1));
+}
+
+#[test]
+fn fleets_in_flight_are_dropped_without_an_heir() {
+    let (mut executor, _) = start_game();
+    let (target, landing) = launch_fleet(&mut executor, 0);
+    let defender = executor.game.state.as_ref().unwrap().planets[target].possession;
+    executor.surrender(0, None).unwrap();
+    assert_eq!(fleets_of(&executor, 0), 0);
+    executor.step_to(landing + 1).unwrap();
+    assert_eq!(planets_of(&executor, Some(0)), 0);
+    let galaxy = executor.game.state.as_ref().unwrap();
+    assert_eq!(galaxy.planets[target].possession, defender);
+}
+
+#[test]
+fn surrenders_are_checked() {
+    let (mut executor, eliminated) = start_game();
+    assert!(executor.surrender(0, Some(0)).is_err());
+    assert!(executor.surrender(0, Some(7)).is_err());
+    assert!(executor.surrender(7, None).is_err());
+    assert_eq!(executor.game.players.len(), 3);
+    assert!(eliminated.lock().unwrap().is_empty());
+}
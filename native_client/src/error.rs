@@ -0,0 +1,44 @@
+use std::fmt;
+use tokio_tungstenite::tungstenite;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The websocket failed, or could not be opened.
+    Socket(tungstenite::Error),
+    /// The server sent a message that does not match the protocol.
+    Parse(serde_json::Error),
+    /// The server rejected a request.
+    Rejected {
+        request: &'static str,
+        message: String,
+    },
+    /// The server closed the connection.
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Socket(e) => write!(f, "Websocket error: {}", e),
+            ClientError::Parse(e) => write!(f, "Could not parse message from server: {}", e),
+            ClientError::Rejected { request, message } => {
+                write!(f, "{} rejected by server: {}", request, message)
+            }
+            ClientError::Closed => write!(f, "The server closed the connection."),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(e: tungstenite::Error) -> Self {
+        ClientError::Socket(e)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        ClientError::Parse(e)
+    }
+}
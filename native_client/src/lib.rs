@@ -0,0 +1,346 @@
+//! Native client for the Inter Planet Game server.
+//!
+//! Wraps the websocket protocol with typed requests and a stream of the
+//! messages the server sends, while keeping a local copy of the joined game
+//! in sync. Intended for bots, load tests and integration tests.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use ipg_core::game::{DisconnectPolicy, GameConfig};
+//! use ipg_core::protocol::messages::MessageType;
+//! use ipg_native_client::{ClientError, GameClient};
+//!
+//! async fn play() -> Result<(), ClientError> {
+//!     let mut client = GameClient::connect("ws://localhost:1234").await?;
+//!     client.set_name("Bot").await?;
+//!     let config = GameConfig {
+//!         min_players: 2,
+//!         disconnect_policy: DisconnectPolicy::Bot,
+//!     };
+//!     client.create_game("Trio", config).await?;
+//!     while let Some(envelope) = client.next().await {
+//!         if let MessageType::NewGame(metadata) = envelope?.message {
+//!             client.enter_game(&metadata.game_id).await?;
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+use futures::{future, SinkExt, Stream};
+use ipg_core::game::{get_millis, GameConfig, SimulationError};
+use ipg_core::protocol::clock_sync::{ClockSync, SYNC_ROUNDS};
+use ipg_core::protocol::messages::{
+    ChatScope, CreateGame, EnterGame, Envelope, GameMove, GameQuery, MessageType, RequestId,
+    SendChat, SetName, Surrender, TimeSync, Visibility,
+};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, WebSocketStream};
+
+mod error;
+mod state;
+pub use self::error::ClientError;
+pub use self::state::{ClientState, CurrentGame};
+
+pub struct GameClient {
+    socket: WebSocketStream<TcpStream>,
+    next_request_id: RequestId,
+    // Messages received while waiting on a reply, not yet yielded by the stream
+    buffered: VecDeque<Envelope>,
+    clock_sync: ClockSync,
+    state: ClientState,
+}
+
+impl GameClient {
+    /// Connects to a game server, e.g. `ws://localhost:1234`.
+    pub async fn connect(url: &str) -> Result<GameClient, ClientError> {
+        let (socket, _) = connect_async(url).await?;
+        Ok(GameClient {
+            socket,
+            next_request_id: 0,
+            buffered: VecDeque::new(),
+            clock_sync: ClockSync::default(),
+            state: ClientState::default(),
+        })
+    }
+
+    /// The client's view of the server, as of the last message received.
+    pub fn state(&self) -> &ClientState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut ClientState {
+        &mut self.state
+    }
+
+    async fn send(&mut self, envelope: Envelope) -> Result<(), ClientError> {
+        let message = Message::from(serde_json::to_string(&envelope)?);
+        self.socket.send(message).await?;
+        Ok(())
+    }
+
+    /// Sends a request tagged with a fresh request id without waiting for the reply.
+    pub async fn send_request(&mut self, message: MessageType) -> Result<RequestId, ClientError> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.send(Envelope {
+            request_id: Some(request_id),
+            message,
+        })
+        .await?;
+        Ok(request_id)
+    }
+
+    /// Sends a request and waits until the server acknowledges or rejects it.
+    /// Other messages received in the meantime are kept for the stream.
+    pub async fn request(&mut self, message: MessageType) -> Result<(), ClientError> {
+        let kind = message.kind();
+        let request_id = self.send_request(message).await?;
+        loop {
+            let envelope = self.receive().await?;
+            if envelope.request_id == Some(request_id) {
+                match envelope.message {
+                    MessageType::Ack => return Ok(()),
+                    MessageType::Error(message) => {
+                        return Err(ClientError::Rejected {
+                            request: kind,
+                            message,
+                        })
+                    }
+                    _ => (),
+                }
+            }
+            self.buffered.push_back(envelope);
+        }
+    }
+
+    pub async fn set_name(&mut self, name: &str) -> Result<(), ClientError> {
+        self.request(MessageType::SetName(SetName {
+            name: name.to_owned(),
+        }))
+        .await
+    }
+
+    /// Creates a public game. The server replies with `GameCreated`.
+    pub async fn create_game(
+        &mut self,
+        map_id: &str,
+        config: GameConfig,
+    ) -> Result<(), ClientError> {
+        self.create_private_game(map_id, config, Visibility::Public)
+            .await
+    }
+
+    pub async fn create_private_game(
+        &mut self,
+        map_id: &str,
+        config: GameConfig,
+        visibility: Visibility,
+    ) -> Result<(), ClientError> {
+        self.request(MessageType::CreateGame(CreateGame {
+            map_id: map_id.to_owned(),
+            config,
+            visibility,
+        }))
+        .await
+    }
+
+    /// Asks for a page of the server's games. The reply is a `GameList`,
+    /// which replaces `state().games`.
+    pub async fn query_games(&mut self, query: GameQuery) -> Result<(), ClientError> {
+        self.request(MessageType::QueryGames(query)).await
+    }
+
+    /// Enters a game, rejoining with the code from a previous visit if there
+    /// is one, and otherwise using its invite code if the client has one.
+    pub async fn enter_game(&mut self, game_id: &str) -> Result<(), ClientError> {
+        self.enter_game_with_password(game_id, None).await
+    }
+
+    pub async fn enter_game_with_password(
+        &mut self,
+        game_id: &str,
+        password: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let rejoin_code = self.state.rejoin_codes.get(game_id).cloned();
+        let invite_code = self.state.invite_codes.get(game_id).cloned();
+        self.request(MessageType::EnterGame(EnterGame {
+            game_id: game_id.to_owned(),
+            rejoin_code,
+            password: password.map(str::to_owned),
+            invite_code,
+            spectate: false,
+        }))
+        .await
+    }
+
+    /// Watches a game without taking part in it.
+    pub async fn spectate_game(
+        &mut self,
+        game_id: &str,
+        password: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let invite_code = self.state.invite_codes.get(game_id).cloned();
+        self.request(MessageType::EnterGame(EnterGame {
+            game_id: game_id.to_owned(),
+            rejoin_code: None,
+            password: password.map(str::to_owned),
+            invite_code,
+            spectate: true,
+        }))
+        .await
+    }
+
+    pub async fn start_game(&mut self) -> Result<(), ClientError> {
+        self.request(MessageType::StartGame).await
+    }
+
+    pub async fn set_ready(&mut self, ready: bool) -> Result<(), ClientError> {
+        self.request(MessageType::SetReady(ready)).await
+    }
+
+    /// Host only: removes the player with the given possession before the game starts.
+    pub async fn kick_player(&mut self, possession: u32) -> Result<(), ClientError> {
+        self.request(MessageType::KickPlayer(possession)).await
+    }
+
+    /// Host only: makes the player with the given possession the host.
+    pub async fn transfer_host(&mut self, possession: u32) -> Result<(), ClientError> {
+        self.request(MessageType::TransferHost(possession)).await
+    }
+
+    /// Host only: changes the map and configuration before the game starts.
+    pub async fn update_game_settings(
+        &mut self,
+        map_id: &str,
+        config: GameConfig,
+    ) -> Result<(), ClientError> {
+        self.request(MessageType::UpdateGameSettings(CreateGame {
+            map_id: map_id.to_owned(),
+            config,
+            // Ignored by the server, visibility is fixed at creation
+            visibility: Visibility::default(),
+        }))
+        .await
+    }
+
+    /// Gives up the game, leaving the player's planets to the heir or making
+    /// them neutral. The client keeps watching the game.
+    pub async fn surrender(&mut self, heir: Option<u32>) -> Result<(), ClientError> {
+        self.request(MessageType::Surrender(Surrender { heir }))
+            .await
+    }
+
+    /// Sends half the ships on planet `from` to planet `to`.
+    pub async fn game_move(&mut self, from: u16, to: u16) -> Result<(), ClientError> {
+        self.request(MessageType::GameMove(GameMove { from, to }))
+            .await
+    }
+
+    pub async fn send_chat(&mut self, scope: ChatScope, text: &str) -> Result<(), ClientError> {
+        self.request(MessageType::SendChat(SendChat {
+            scope,
+            text: text.to_owned(),
+        }))
+        .await
+    }
+
+    /// Estimates the offset between the local and server clocks, which is
+    /// needed to follow the game's ticks.
+    pub async fn sync_clock(&mut self) -> Result<(), ClientError> {
+        self.clock_sync.reset();
+        for _ in 0..SYNC_ROUNDS {
+            self.send(Envelope::from(MessageType::Time(TimeSync::request(
+                get_millis(),
+            ))))
+            .await?;
+            loop {
+                let envelope = self.receive().await?;
+                if let MessageType::Time(reply) = &envelope.message {
+                    self.clock_sync.add_reply(reply, get_millis());
+                    break;
+                }
+                self.buffered.push_back(envelope);
+            }
+        }
+        Ok(())
+    }
+
+    /// Round trip latency to the server in milliseconds, once the clock is synchronised.
+    pub fn latency(&self) -> Option<f64> {
+        self.clock_sync.round_trip()
+    }
+
+    /// The current tick of the joined game according to the server's clock.
+    pub fn game_tick(&self) -> Option<u32> {
+        let clock = self.state.current_game.as_ref()?.clock.as_ref()?;
+        self.clock_sync.game_tick(clock, get_millis())
+    }
+
+    /// Advances the local copy of the game to the current server tick. If
+    /// this fails, `sync_game` replaces the local copy with the server's.
+    pub fn step_to_now(&mut self) -> Result<(), SimulationError> {
+        if let Some(tick) = self.game_tick() {
+            let exec = self
+                .state
+                .current_game
+                .as_mut()
+                .and_then(|current| current.exec.as_mut());
+            if let Some(exec) = exec {
+                let time = exec.game.state.as_ref().map_or(tick, |s| s.time.max(tick));
+                exec.step_to(time)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the server for a fresh copy of the current game.
+    pub async fn sync_game(&mut self) -> Result<(), ClientError> {
+        self.request(MessageType::SyncGame).await
+    }
+
+    async fn receive(&mut self) -> Result<Envelope, ClientError> {
+        future::poll_fn(|cx| self.poll_receive(cx))
+            .await
+            .unwrap_or(Err(ClientError::Closed))
+    }
+
+    fn poll_receive(&mut self, cx: &mut Context) -> Poll<Option<Result<Envelope, ClientError>>> {
+        loop {
+            let message = match Pin::new(&mut self.socket).poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => message,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match message {
+                Message::Text(body) => {
+                    let envelope = serde_json::from_str::<Envelope>(&body);
+                    if let Ok(envelope) = &envelope {
+                        self.state.apply(&envelope.message);
+                    }
+                    return Poll::Ready(Some(envelope.map_err(ClientError::from)));
+                }
+                Message::Close(_) => return Poll::Ready(None),
+                // Control frames are handled by tungstenite
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Yields every message the server sends, after it has been applied to the client's state.
+impl Stream for GameClient {
+    type Item = Result<Envelope, ClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let client = self.get_mut();
+        if let Some(envelope) = client.buffered.pop_front() {
+            return Poll::Ready(Some(Ok(envelope)));
+        }
+        client.poll_receive(cx)
+    }
+}
@@ -0,0 +1,127 @@
+use ipg_core::game::{map::Map, GameClock, GameExecutor, Heartbeat, Player};
+use ipg_core::protocol::messages::{
+    ChatMessage, ChatScope, EnterGame, GameCreated, GameList, GameMetadata, InviteCode,
+    MessageType, RejoinCode,
+};
+use std::collections::HashMap;
+
+/// The client's view of the server, updated from every message received.
+#[derive(Default)]
+pub struct ClientState {
+    pub games: Vec<GameMetadata>,
+    pub maps: HashMap<String, Map>,
+    /// Rejoin codes handed out by the server, by game id
+    pub rejoin_codes: HashMap<String, RejoinCode>,
+    /// Invite codes of games this client created, by game id. Add codes
+    /// shared by others here to join their unlisted games.
+    pub invite_codes: HashMap<String, InviteCode>,
+    pub current_game: Option<CurrentGame>,
+    /// Chat messages from the lobby and the current game, oldest first
+    pub chat: Vec<ChatMessage>,
+}
+
+/// The game the client has entered.
+pub struct CurrentGame {
+    pub game_id: String,
+    pub possession: Option<usize>,
+    pub players: Vec<Player>,
+    pub clock: Option<GameClock>,
+    /// Local copy of the game, present once the server has sent it
+    pub exec: Option<GameExecutor>,
+    /// The server's latest view of the planets, while the game is running
+    pub heartbeat: Option<Heartbeat>,
+}
+
+impl ClientState {
+    pub(crate) fn apply(&mut self, message: &MessageType) {
+        match message {
+            MessageType::GameList(GameList { games, .. }) => self.games = games.clone(),
+            MessageType::NewGame(metadata) => self.games.push(metadata.clone()),
+            MessageType::GameCreated(GameCreated { game, invite_code }) => {
+                self.invite_codes
+                    .insert(game.game_id.clone(), invite_code.clone());
+            }
+            MessageType::UpdateGame(metadata) => {
+                for game in self.games.iter_mut() {
+                    if game.game_id == metadata.game_id {
+                        *game = metadata.clone();
+                    }
+                }
+            }
+            MessageType::RemoveGame(game_id) => {
+                self.games.retain(|game| &game.game_id != game_id);
+                if self.current_game.as_ref().map(|current| &current.game_id) == Some(game_id) {
+                    self.current_game = None;
+                }
+            }
+            MessageType::MapList(maps) => self.maps = maps.clone(),
+            MessageType::Chat(message) => self.chat.push(message.clone()),
+            MessageType::ChatHistory(messages) => self.chat.extend(messages.iter().cloned()),
+            MessageType::EnterGame(EnterGame {
+                game_id,
+                rejoin_code,
+                ..
+            }) => {
+                if let Some(rejoin_code) = rejoin_code {
+                    self.rejoin_codes
+                        .insert(game_id.clone(), rejoin_code.clone());
+                }
+                self.chat
+                    .retain(|message| message.scope == ChatScope::Lobby);
+                self.current_game = Some(CurrentGame {
+                    game_id: game_id.clone(),
+                    possession: None,
+                    players: Vec::new(),
+                    clock: None,
+                    exec: None,
+                    heartbeat: None,
+                });
+            }
+            MessageType::ExitGame => self.current_game = None,
+            MessageType::Eliminated(standing) => {
+                if let Some(current) = self.current_game.as_mut() {
+                    if current.possession == Some(standing.possession) {
+                        // Players who surrendered watch the rest of the game
+                        current.possession = None;
+                        self.rejoin_codes.remove(&current.game_id);
+                    }
+                }
+            }
+            MessageType::Kicked(game_id) => {
+                self.rejoin_codes.remove(game_id);
+                self.chat
+                    .retain(|message| message.scope == ChatScope::Lobby);
+                self.current_game = None;
+            }
+            message => {
+                if let Some(current) = self.current_game.as_mut() {
+                    current.apply(message);
+                }
+            }
+        }
+    }
+}
+
+impl CurrentGame {
+    fn apply(&mut self, message: &MessageType) {
+        match message {
+            MessageType::Possession(possession) => self.possession = Some(*possession as usize),
+            MessageType::GamePlayers(players) => {
+                self.players = players.clone();
+                if let Some(exec) = self.exec.as_mut() {
+                    exec.game.players = players.clone();
+                }
+            }
+            MessageType::GameClock(clock) => self.clock = Some(clock.clone()),
+            MessageType::Heartbeat(heartbeat) => self.heartbeat = Some(heartbeat.clone()),
+            MessageType::Game(game) => match self.exec.as_mut() {
+                Some(exec) => exec.set_game(game.clone()),
+                None => {
+                    self.players = game.players.clone();
+                    self.exec = Some(GameExecutor::from_game(game.clone(), self.game_id.clone()));
+                }
+            },
+            _ => (),
+        }
+    }
+}
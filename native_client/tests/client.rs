@@ -0,0 +1,78 @@
+//! Tests the client against a stand-in server that acknowledges names and
+//! chat messages, echoing chat back, and rejects everything else.
+
+use futures::{SinkExt, StreamExt};
+use ipg_core::protocol::messages::{ChatMessage, ChatScope, Envelope, MessageType, SendChat};
+use ipg_native_client::{ClientError, GameClient};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// How long to wait for the stand-in server before failing the test
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts the stand-in server, returning its url.
+async fn start_server() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = socket.next().await {
+            let envelope: Envelope = serde_json::from_str(&text).unwrap();
+            let mut replies = Vec::new();
+            let reply = match envelope.message {
+                MessageType::SetName(_) => MessageType::Ack,
+                MessageType::SendChat(SendChat { scope, text }) => {
+                    replies.push(Envelope::from(MessageType::Chat(ChatMessage {
+                        scope,
+                        sender: "Server".to_owned(),
+                        text,
+                        time: 0,
+                    })));
+                    MessageType::Ack
+                }
+                _ => MessageType::Error("Not supported.".to_owned()),
+            };
+            replies.push(Envelope::reply(envelope.request_id, reply));
+            for reply in replies {
+                let frame = Message::from(serde_json::to_string(&reply).unwrap());
+                socket.send(frame).await.unwrap();
+            }
+        }
+    });
+    format!("ws://{}", address)
+}
+
+#[tokio::test]
+async fn requests_wait_for_their_acknowledgement() {
+    let url = start_server().await;
+    let mut client = GameClient::connect(&url).await.unwrap();
+    client.set_name("Tester").await.unwrap();
+    client.send_chat(ChatScope::Lobby, "Hello").await.unwrap();
+    // The chat message arrived before the acknowledgement, and was kept for the stream
+    let envelope = tokio::time::timeout(TIMEOUT, client.next())
+        .await
+        .expect("Timed out waiting for the server")
+        .unwrap()
+        .unwrap();
+    assert!(envelope.request_id.is_none());
+    assert!(matches!(envelope.message, MessageType::Chat(message) if message.text == "Hello"));
+    assert_eq!(client.state().chat.len(), 1);
+}
+
+#[tokio::test]
+async fn rejected_requests_return_the_reason() {
+    let url = start_server().await;
+    let mut client = GameClient::connect(&url).await.unwrap();
+    match client.start_game().await {
+        Err(ClientError::Rejected { request, message }) => {
+            assert_eq!(request, "StartGame");
+            assert_eq!(message, "Not supported.");
+        }
+        _ => panic!("StartGame should have been rejected"),
+    }
+    // The client can carry on after a rejection
+    client.set_name("Tester").await.unwrap();
+}
@@ -0,0 +1,413 @@
+use ipg_core::game::TICK_LENGTH;
+use serde_derive::Deserialize;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+const LOG_FORMATS: [&str; 2] = ["human", "json"];
+
+/// Declares every setting once, generating `ServerConfig` with the setting
+/// and its default, and `Options` with a flag and environment variable for
+/// it. Settings without a default are optional.
+macro_rules! settings {
+    ($(
+        $(#[doc = $doc:literal])*
+        #[structopt($($flag:tt)*)]
+        $name:ident: $kind:ty $(= $default:expr)?,
+    )*) => {
+        /// Command line flags. Each flag can also be given through its environment
+        /// variable, and both take precedence over the config file.
+        #[derive(StructOpt, Debug)]
+        #[structopt(name = "ipg_server", about = "Hosts Inter Planet Game games.")]
+        pub struct Options {
+            /// TOML file to read the server configuration from
+            #[structopt(short, long, env = "IPG_CONFIG", parse(from_os_str))]
+            pub config: Option<PathBuf>,
+            $(
+                $(#[doc = $doc])*
+                #[structopt($($flag)*)]
+                pub $name: Option<$kind>,
+            )*
+        }
+
+        #[derive(Deserialize, Debug, Clone)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct ServerConfig {
+            $(
+                $(#[doc = $doc])*
+                pub $name: setting!(type $kind $(, $default)?),
+            )*
+        }
+
+        impl Default for ServerConfig {
+            fn default() -> Self {
+                ServerConfig {
+                    $($name: setting!(default $($default)?),)*
+                }
+            }
+        }
+
+        impl ServerConfig {
+            /// Replaces the settings given as flags or environment variables.
+            fn apply(&mut self, options: Options) {
+                $(
+                    if let Some(value) = options.$name {
+                        self.$name = setting!(value value $(, $default)?);
+                    }
+                )*
+            }
+        }
+    };
+}
+
+/// How `settings!` treats a setting, depending on whether it has a default.
+macro_rules! setting {
+    (type $kind:ty) => { Option<$kind> };
+    (type $kind:ty, $default:expr) => { $kind };
+    (default) => { None };
+    (default $default:expr) => { $default };
+    (value $value:ident) => { Some($value) };
+    (value $value:ident, $default:expr) => { $value };
+}
+
+settings! {
+    /// Address to listen on
+    #[structopt(long, env = "IPG_BIND_ADDRESS")]
+    bind_address: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+    /// Port to listen on
+    #[structopt(short, long, env = "IPG_PORT")]
+    port: u16 = 1234,
+    /// Directory containing the map files
+    #[structopt(long, env = "IPG_MAPS_DIR")]
+    maps_dir: String,
+    /// Most games hosted at once
+    #[structopt(long, env = "IPG_MAX_GAMES")]
+    max_games: usize = 256,
+    /// Most clients connected at once
+    #[structopt(long, env = "IPG_MAX_CONNECTIONS")]
+    max_connections: usize = 1024,
+    /// Seconds a client has to complete the websocket handshake
+    #[structopt(long, env = "IPG_HANDSHAKE_TIMEOUT")]
+    handshake_timeout: u64 = 10,
+    /// Largest websocket message accepted from a client, in bytes
+    #[structopt(long, env = "IPG_MAX_MESSAGE_SIZE")]
+    max_message_size: usize = 64 * 1024,
+    /// Most messages a client can send per second
+    #[structopt(long, env = "IPG_MESSAGES_PER_SECOND")]
+    messages_per_second: u32 = 20,
+    /// Most moves a player can make per second
+    #[structopt(long, env = "IPG_MOVES_PER_SECOND")]
+    moves_per_second: u32 = 10,
+    /// Most games a client can create per minute
+    #[structopt(long, env = "IPG_GAMES_PER_MINUTE")]
+    games_per_minute: u32 = 5,
+    /// Most games a client can host at once
+    #[structopt(long, env = "IPG_MAX_GAMES_PER_CONNECTION")]
+    max_games_per_connection: usize = 3,
+    /// Rate limited messages a client can send within a minute before it is disconnected
+    #[structopt(long, env = "IPG_MAX_RATE_LIMIT_VIOLATIONS")]
+    max_rate_limit_violations: u32 = 20,
+    /// Most outgoing messages queued for a single connection
+    #[structopt(long, env = "IPG_CHANNEL_SIZE")]
+    channel_size: usize = 128,
+    /// Game ticks per second. Ticks last a whole number of milliseconds, so
+    /// rates that don't divide 1000 are rounded to the nearest tick length.
+    #[structopt(long, env = "IPG_TICK_RATE")]
+    tick_rate: u32 = 1000 / TICK_LENGTH,
+    /// Milliseconds between heartbeats with the state of a running game
+    #[structopt(long, env = "IPG_HEARTBEAT_INTERVAL")]
+    heartbeat_interval: u64 = 1000,
+    /// One of off, error, warn, info, debug or trace
+    #[structopt(long, env = "IPG_LOG_LEVEL")]
+    log_level: String = "info".to_owned(),
+    /// Either human, or json for one JSON object per line
+    #[structopt(long, env = "IPG_LOG_FORMAT")]
+    log_format: String = "human".to_owned(),
+    /// Port to serve Prometheus metrics on at /metrics, to localhost only
+    #[structopt(long, env = "IPG_METRICS_PORT")]
+    metrics_port: u16,
+    /// Directory to save games to, so they survive a restart
+    #[structopt(long, env = "IPG_SNAPSHOT_DIR")]
+    snapshot_dir: String,
+    /// Seconds between game snapshots
+    #[structopt(long, env = "IPG_SNAPSHOT_INTERVAL")]
+    snapshot_interval: u64 = 30,
+    /// Directory to write crash dumps of games that hit a bug to
+    #[structopt(long, env = "IPG_CRASH_DUMP_DIR")]
+    crash_dump_dir: String = "./crash_dumps".to_owned(),
+    /// Seconds a disconnected player can rejoin their game for
+    #[structopt(long, env = "IPG_SESSION_LIFETIME")]
+    session_lifetime: u64 = 24 * 60 * 60,
+    /// Seconds a disconnected player's seat is held in a running game
+    #[structopt(long, env = "IPG_DISCONNECT_GRACE_PERIOD")]
+    disconnect_grace_period: u64 = 60,
+    /// Seconds between clean ups of abandoned games
+    #[structopt(long, env = "IPG_CLEANUP_INTERVAL")]
+    cleanup_interval: u64 = 30,
+    /// Seconds a game waiting to start can go without anyone joining,
+    /// readying up, changing settings or chatting before it is removed
+    #[structopt(long, env = "IPG_LOBBY_TIMEOUT")]
+    lobby_timeout: u64 = 30 * 60,
+    /// Seconds a player can go without making a move before losing their seat
+    #[structopt(long, env = "IPG_IDLE_TIMEOUT")]
+    idle_timeout: u64 = 10 * 60,
+    /// Seconds a finished game is kept around before it is removed
+    #[structopt(long, env = "IPG_FINISHED_GAME_TIMEOUT")]
+    finished_game_timeout: u64 = 5 * 60,
+}
+
+impl ServerConfig {
+    /// Reads the config file named by the options, if any, then applies the
+    /// options on top of it.
+    pub fn load(options: Options) -> Result<ServerConfig, String> {
+        let mut config = match &options.config {
+            Some(path) => ServerConfig::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+        config.apply(options);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<ServerConfig, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read config file {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let maps_dir = self
+            .maps_dir
+            .as_ref()
+            .ok_or("No maps directory configured, set maps_dir, --maps-dir or IPG_MAPS_DIR.")?;
+        if !Path::new(maps_dir).is_dir() {
+            return Err(format!("Maps directory {} not found.", maps_dir));
+        }
+        if self.max_games == 0 {
+            return Err("max_games must be at least 1.".to_owned());
+        }
+        if self.max_connections == 0 {
+            return Err("max_connections must be at least 1.".to_owned());
+        }
+        if self.handshake_timeout == 0 {
+            return Err("handshake_timeout must be at least 1 second.".to_owned());
+        }
+        if self.max_message_size == 0 {
+            return Err("max_message_size must be at least 1 byte.".to_owned());
+        }
+        if self.messages_per_second == 0 || self.moves_per_second == 0 || self.games_per_minute == 0
+        {
+            return Err(
+                "messages_per_second, moves_per_second and games_per_minute must be at least 1."
+                    .to_owned(),
+            );
+        }
+        if self.max_games_per_connection == 0 {
+            return Err("max_games_per_connection must be at least 1.".to_owned());
+        }
+        if self.channel_size == 0 {
+            return Err("channel_size must be at least 1.".to_owned());
+        }
+        if self.tick_rate == 0 || self.tick_rate > 1000 {
+            return Err("tick_rate must be between 1 and 1000 ticks per second.".to_owned());
+        }
+        if self.heartbeat_interval == 0 {
+            return Err("heartbeat_interval must be at least 1 millisecond.".to_owned());
+        }
+        if !LOG_LEVELS.contains(&self.log_level.to_lowercase().as_str()) {
+            return Err(format!(
+                "Unknown log level \"{}\", expected one of {}.",
+                self.log_level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        if !LOG_FORMATS.contains(&self.log_format.as_str()) {
+            return Err(format!(
+                "Unknown log format \"{}\", expected one of {}.",
+                self.log_format,
+                LOG_FORMATS.join(", ")
+            ));
+        }
+        if self.snapshot_interval == 0 {
+            return Err("snapshot_interval must be at least 1 second.".to_owned());
+        }
+        if self.session_lifetime == 0 {
+            return Err("session_lifetime must be at least 1 second.".to_owned());
+        }
+        if self.cleanup_interval == 0 {
+            return Err("cleanup_interval must be at least 1 second.".to_owned());
+        }
+        let timeouts = [
+            self.disconnect_grace_period,
+            self.lobby_timeout,
+            self.idle_timeout,
+            self.finished_game_timeout,
+        ];
+        if timeouts.contains(&0) {
+            return Err(concat!(
+                "disconnect_grace_period, lobby_timeout, idle_timeout and ",
+                "finished_game_timeout must be at least 1 second."
+            )
+            .to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    /// Where metrics are served, if anywhere.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        self.metrics_port
+            .map(|port| SocketAddr::new(localhost, port))
+    }
+
+    /// Milliseconds a disconnected player's session stays valid
+    pub fn session_lifetime_millis(&self) -> u128 {
+        self.session_lifetime as u128 * 1000
+    }
+
+    /// Milliseconds a disconnected player's seat is held for
+    pub fn disconnect_grace_period_millis(&self) -> u128 {
+        self.disconnect_grace_period as u128 * 1000
+    }
+
+    pub fn lobby_timeout_millis(&self) -> u128 {
+        self.lobby_timeout as u128 * 1000
+    }
+
+    pub fn idle_timeout_millis(&self) -> u128 {
+        self.idle_timeout as u128 * 1000
+    }
+
+    pub fn finished_game_timeout_millis(&self) -> u128 {
+        self.finished_game_timeout as u128 * 1000
+    }
+
+    /// Milliseconds per game tick, rounded to the nearest millisecond
+    pub fn tick_length(&self) -> u32 {
+        (1000 + self.tick_rate / 2) / self.tick_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Writes a config file for the test, returning its path.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ipg-{}-{}.toml", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn valid_config() -> ServerConfig {
+        ServerConfig {
+            maps_dir: Some("../maps".to_owned()),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn config_files_are_parsed() {
+        let path = config_file("parsed", "port = 4321\nmetrics_port = 9100\n");
+        let config = ServerConfig::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.port, 4321);
+        assert_eq!(config.metrics_port, Some(9100));
+        // Anything left out keeps its default
+        assert_eq!(config.max_games, ServerConfig::default().max_games);
+        assert_eq!(config.snapshot_dir, None);
+
+        let path = config_file("unknown", "prot = 4321\n");
+        let config = ServerConfig::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn flags_override_the_environment_and_the_file() {
+        let path = config_file(
+            "precedence",
+            "maps_dir = \"../maps\"\nmax_games = 1\nlobby_timeout = 5\nidle_timeout = 6\n",
+        );
+        // Nothing else reads these variables
+        env::set_var("IPG_MAX_GAMES", "2");
+        env::set_var("IPG_LOBBY_TIMEOUT", "7");
+        let flags = [
+            "ipg_server",
+            "--config",
+            path.to_str().unwrap(),
+            "--max-games",
+            "3",
+        ];
+        let options = Options::from_iter_safe(&flags);
+        env::remove_var("IPG_MAX_GAMES");
+        env::remove_var("IPG_LOBBY_TIMEOUT");
+        let config = ServerConfig::load(options.unwrap());
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.max_games, 3);
+        assert_eq!(config.lobby_timeout, 7);
+        assert_eq!(config.idle_timeout, 6);
+        assert_eq!(config.maps_dir.as_deref(), Some("../maps"));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        assert!(valid_config().validate().is_ok());
+        assert!(ServerConfig::default().validate().is_err());
+        let invalid = [
+            ServerConfig {
+                disconnect_grace_period: 0,
+                ..valid_config()
+            },
+            ServerConfig {
+                lobby_timeout: 0,
+                ..valid_config()
+            },
+            ServerConfig {
+                idle_timeout: 0,
+                ..valid_config()
+            },
+            ServerConfig {
+                finished_game_timeout: 0,
+                ..valid_config()
+            },
+            ServerConfig {
+                tick_rate: 0,
+                ..valid_config()
+            },
+            ServerConfig {
+                log_level: "loud".to_owned(),
+                ..valid_config()
+            },
+        ];
+        for config in &invalid {
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn tick_lengths_are_rounded() {
+        let tick_length = |tick_rate| {
+            ServerConfig {
+                tick_rate,
+                ..ServerConfig::default()
+            }
+            .tick_length()
+        };
+        assert_eq!(tick_length(50), 20);
+        assert_eq!(tick_length(58), 17);
+        assert_eq!(tick_length(60), 17);
+        assert_eq!(tick_length(1000), 1);
+    }
+}
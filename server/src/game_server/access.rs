@@ -0,0 +1,79 @@
+use ipg_core::protocol::messages::Visibility;
+use rand::rngs::OsRng;
+use rand::{distributions::Alphanumeric, Rng};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Who may join a game, as the server keeps it. Unlike `Visibility`, a
+/// password is never kept, only its hash, since games are saved to disk.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Access {
+    Public,
+    Unlisted,
+    Password(PasswordHash),
+}
+
+impl Access {
+    /// Whether the game shows up in the lobby's game list.
+    pub fn is_listed(&self) -> bool {
+        !matches!(self, Access::Unlisted)
+    }
+}
+
+impl From<Visibility> for Access {
+    fn from(visibility: Visibility) -> Self {
+        match visibility {
+            Visibility::Public => Access::Public,
+            Visibility::Unlisted => Access::Unlisted,
+            Visibility::Password(password) => Access::Password(PasswordHash::new(&password)),
+        }
+    }
+}
+
+/// A salted SHA-256 hash of a game's password.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PasswordHash {
+    salt: String,
+    hash: String,
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> Self {
+        let mut rng =
+            OsRng::new().expect("Unable to access the operating system's random numbers.");
+        let salt: String = rng.sample_iter(&Alphanumeric).take(16).collect();
+        let hash = hash(&salt, password);
+        PasswordHash { salt, hash }
+    }
+
+    pub fn matches(&self, password: &str) -> bool {
+        hash(&self.salt, password) == self.hash
+    }
+}
+
+fn hash(salt: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_only_kept_hashed() {
+        let access = Access::from(Visibility::Password("hunter2".to_owned()));
+        let saved = serde_json::to_string(&access).unwrap();
+        assert!(!saved.contains("hunter2"));
+        let access: Access = serde_json::from_str(&saved).unwrap();
+        match access {
+            Access::Password(hash) => {
+                assert!(hash.matches("hunter2"));
+                assert!(!hash.matches("hunter3"));
+            }
+            _ => panic!("The password was lost"),
+        }
+    }
+}
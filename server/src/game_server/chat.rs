@@ -0,0 +1,70 @@
+use ipg_core::protocol::messages::ChatMessage;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Longest chat message accepted, in characters.
+pub const MAX_CHAT_LENGTH: usize = 280;
+/// Number of chat messages kept for players who join later
+const CHAT_HISTORY_LENGTH: usize = 50;
+/// Number of chat messages a connection may send within `RATE_WINDOW`.
+const RATE_LIMIT: usize = 5;
+/// Milliseconds
+const RATE_WINDOW: u128 = 10_000;
+
+/// Checks chat text is acceptable, returning it trimmed of surrounding whitespace.
+pub fn validate_chat(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        Err("Chat messages cannot be empty.".to_owned())
+    } else if text.chars().count() > MAX_CHAT_LENGTH {
+        Err(format!(
+            "Chat messages cannot be longer than {} characters.",
+            MAX_CHAT_LENGTH
+        ))
+    } else {
+        Ok(text.to_owned())
+    }
+}
+
+/// Stops a single connection from flooding the chat.
+#[derive(Default)]
+pub struct ChatLimiter {
+    sent: VecDeque<u128>,
+}
+
+impl ChatLimiter {
+    /// Records a message sent at `now`, unless the connection is over its limit.
+    pub fn check(&mut self, now: u128) -> Result<(), String> {
+        while let Some(&time) = self.sent.front() {
+            if now.saturating_sub(time) < RATE_WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= RATE_LIMIT {
+            Err("You are sending chat messages too quickly.".to_owned())
+        } else {
+            self.sent.push_back(now);
+            Ok(())
+        }
+    }
+}
+
+/// The most recent messages of a chat, which are sent to clients as they join.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ChatLog {
+    messages: VecDeque<ChatMessage>,
+}
+
+impl ChatLog {
+    pub fn push(&mut self, message: ChatMessage) {
+        if self.messages.len() >= CHAT_HISTORY_LENGTH {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    pub fn history(&self) -> Vec<ChatMessage> {
+        self.messages.iter().cloned().collect()
+    }
+}
@@ -1,62 +1,133 @@
+use super::access::Access;
+use super::game_actor::GameHandle;
+use super::{encode, game_metadata};
 use crate::GameServer;
-use futures::{stream, StreamExt};
-use ipg_core::game::{Game, GameEvent, GameExecutor, Player};
-use ipg_core::protocol::messages::{EnterGame, GameList, GameMetadata, MessageType};
+use ipg_core::game::{get_millis, Game, GameEvent, GameExecutor, HandlerId, Player};
+use ipg_core::protocol::messages::{
+    ChatMessage, ChatScope, EnterGame, Envelope, GameQuery, GameStatus, MessageType, RejoinCode,
+    SendChat, Surrender, TimeSync,
+};
 use std::borrow::BorrowMut;
 use std::future::Future;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc::Sender, Mutex};
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
 use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, field, info_span, warn, Instrument};
 
-use super::rejoin::generate_rejoin_code;
+use super::chat::{validate_chat, ChatLimiter};
+use super::outbox::Outbox;
+use super::rate_limit::{RateLimiter, Violation};
 
 pub trait Captures<'a> {}
 
 impl<'a, T> Captures<'a> for T {}
 
 pub struct GameConnection {
+    /// Tells the connection apart from others watching the same game
+    connection_id: usize,
     player: Option<Player>,
-    current_game: Option<Arc<Mutex<GameExecutor>>>,
-    sink: Sender<Message>,
+    current_game: Option<GameHandle>,
+    sink: Outbox,
     instance: Arc<GameServer>,
+    chat_limiter: ChatLimiter,
+    rate_limiter: RateLimiter,
+    /// Games created by this connection, which it hosts on entry
+    created_games: Vec<String>,
+    /// Cleared by the game's event handler when the player is kicked
+    seated: Arc<AtomicBool>,
+    /// Sends the current game's events to the connection, until it leaves
+    event_handler: Option<HandlerId>,
+    /// Whether the connection is watching its current game rather than playing
+    spectating: bool,
+    /// The game id and rejoin session of the seat the connection holds
+    session: Option<(String, RejoinCode)>,
+}
+
+/// What a game's event handler knows about the player it sends events to.
+struct GameSeat {
+    game_id: String,
+    /// None for spectators
+    possession: Option<usize>,
+    seated: Arc<AtomicBool>,
 }
 
 impl GameConnection {
-    pub fn new(instance: Arc<GameServer>, sink: Sender<Message>) -> Self {
+    pub fn new(instance: Arc<GameServer>, connection_id: usize, sink: Outbox) -> Self {
         GameConnection {
+            connection_id,
             player: None,
             current_game: None,
             sink: sink,
+            chat_limiter: ChatLimiter::default(),
+            rate_limiter: RateLimiter::new(&instance.config, get_millis()),
+            created_games: Vec::new(),
+            seated: Arc::new(AtomicBool::new(false)),
+            event_handler: None,
+            spectating: false,
+            session: None,
             instance,
         }
     }
 
-    fn handle_game_event(mut sink: Sender<Message>, game: &mut Game, event: &GameEvent) {
+    fn handle_game_event(sink: &Outbox, seat: &GameSeat, game: &mut Game, event: &GameEvent) {
         //let mut executor = executor.lock().unwrap();
+        if !seat.seated.load(Ordering::SeqCst) {
+            return;
+        }
         match event {
-            GameEvent::Start => {
-                let seralized = serde_json::to_string(&MessageType::StartGame).unwrap();
-                let seralized2 = serde_json::to_string(&MessageType::Game(game.clone())).unwrap();
-                tokio::spawn(async move {
-                    let _ = sink.send(Message::from(seralized)).await;
-                    let _ = sink.send(Message::from(seralized2)).await;
-                });
+            GameEvent::Start(clock) => {
+                sink.send(encode(MessageType::StartGame));
+                sink.send(encode(MessageType::GameClock(clock.clone())));
+                sink.send(encode(MessageType::Game(game.clone())));
             }
             GameEvent::Move(_game_move) => {
                 // let seralized =
                 //     serde_json::to_string(&MessageType::TimedGameMove(game_move.clone())).unwrap();
                 // sink.start_send(Message::from(seralized));
-                let seralized = serde_json::to_string(&MessageType::Game(game.clone())).unwrap();
-                tokio::spawn(async move {
-                    let _ = sink.send(Message::from(seralized)).await;
-                });
+                sink.send(encode(MessageType::Game(game.clone())));
             }
-            GameEvent::PlayerLeave(_) | GameEvent::Player(_) => {
-                let seralized =
-                    serde_json::to_string(&MessageType::GamePlayers(game.players.clone())).unwrap();
-                tokio::spawn(async move {
-                    let _ = sink.send(Message::from(seralized)).await;
-                });
+            GameEvent::Chat(message) => {
+                sink.send(encode(MessageType::Chat(message.clone())));
+            }
+            GameEvent::Crashed(message) => {
+                seat.seated.store(false, Ordering::SeqCst);
+                sink.send(encode(MessageType::Error(message.clone())));
+            }
+            GameEvent::Kick(player) if Some(player.possession) == seat.possession => {
+                seat.seated.store(false, Ordering::SeqCst);
+                sink.send(encode(MessageType::Kicked(seat.game_id.clone())));
+            }
+            GameEvent::Metadata(metadata) => {
+                sink.send(encode(MessageType::UpdateGame(metadata.clone())));
+            }
+            GameEvent::Eliminated(standing) => {
+                sink.send(encode(MessageType::Eliminated(standing.clone())));
+            }
+            GameEvent::Arrival(arrival) => {
+                sink.send(encode(MessageType::Arrival(arrival.clone())));
+            }
+            GameEvent::Capture(capture) => {
+                sink.send(encode(MessageType::Capture(capture.clone())));
+            }
+            GameEvent::Heartbeat(heartbeat) => {
+                sink.send(encode(MessageType::Heartbeat(heartbeat.clone())));
+            }
+            GameEvent::Forfeit(_) => {
+                sink.send(encode(MessageType::GamePlayers(game.players.clone())));
+                // Neutral forfeits change the galaxy, which clients can't work out from moves alone
+                if game.state.is_some() {
+                    sink.send(encode(MessageType::Game(game.clone())));
+                }
+            }
+            GameEvent::PlayerLeave(_)
+            | GameEvent::Player(_)
+            | GameEvent::Kick(_)
+            | GameEvent::SettingsChanged
+            | GameEvent::PlayersChanged => {
+                sink.send(encode(MessageType::GamePlayers(game.players.clone())));
             }
         }
     }
@@ -66,39 +137,114 @@ impl GameConnection {
         message: &'a Message,
     ) -> impl Future<Output = ()> + Captures<'a> + Captures<'b> + 'c {
         async move {
-            let result = self.handle_message_internal(message).await;
-            match result {
-                Ok(_) => (),
+            let envelope = match GameConnection::parse_message(message) {
+                Ok(envelope) => envelope,
                 Err(e) => {
-                    let _ = self
-                        .sink
-                        .send(Message::from(
-                            serde_json::to_string(&MessageType::Error(e)).unwrap(),
-                        ))
-                        .await;
+                    debug!("{}", e);
+                    let metrics = &self.instance.metrics;
+                    metrics.invalid_messages.fetch_add(1, Ordering::Relaxed);
+                    self.sink.send(encode(MessageType::Error(e)));
+                    return;
+                }
+            };
+            let kind = envelope.message.kind();
+            self.instance.metrics.messages_received.add(kind);
+            let span = info_span!(
+                "request",
+                kind,
+                game = field::Empty,
+                possession = field::Empty
+            );
+            if let Some(game) = &self.current_game {
+                span.record("game", game.game_id.as_str());
+                if let (Some(player), false) = (&self.player, self.spectating) {
+                    span.record("possession", player.possession);
                 }
             }
+            self.handle_envelope(envelope).instrument(span).await
         }
     }
 
-    async fn handle_message_internal<'a: 'c, 'b: 'c, 'c>(
-        &'b mut self,
-        message: &'a Message,
-    ) -> Result<(), String> {
+    async fn handle_envelope(&mut self, envelope: Envelope) {
+        let request_id = envelope.request_id;
+        let kind = envelope.message.kind();
+        match self.rate_limiter.check(&envelope.message, get_millis()) {
+            Ok(()) => (),
+            Err(Violation::Limited(reason)) => {
+                debug!("{}", reason);
+                self.instance.metrics.request_errors.add(kind);
+                let reply = Envelope::reply(request_id, MessageType::Error(reason));
+                self.sink.send(encode(reply));
+                return;
+            }
+            Err(Violation::Disconnect(reason)) => {
+                warn!("Disconnecting: {}", reason);
+                self.instance.metrics.request_errors.add(kind);
+                self.sink.send(encode(MessageType::Error(reason.clone())));
+                self.sink.send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: reason.into(),
+                })));
+                return;
+            }
+        }
+        let result = self.handle_message_internal(envelope.message).await;
+        match &result {
+            Ok(()) => debug!("Handled"),
+            Err(e) => {
+                debug!("Rejected: {}", e);
+                self.instance.metrics.request_errors.add(kind);
+            }
+        }
+        let reply = match (result, request_id) {
+            // Only requests that carry an id get acknowledged
+            (Ok(_), None) => return,
+            (Ok(_), Some(_)) => MessageType::Ack,
+            (Err(e), _) => MessageType::Error(e),
+        };
+        self.sink.send(encode(Envelope::reply(request_id, reply)));
+    }
+
+    fn parse_message(message: &Message) -> Result<Envelope, String> {
         let message_body = message
             .to_text()
             .map_err(|_| "The recieved message could not be parsed as a string.".to_owned())?;
-        let message_data = serde_json::from_str::<MessageType>(message_body)
-            .map_err(|_| "Could not parse the provided message.".to_owned())?;
-        //Inside message handlers, always lock sinks first to avoid deadlocks
+        serde_json::from_str::<Envelope>(message_body)
+            .map_err(|_| "Could not parse the provided message.".to_owned())
+    }
+
+    async fn handle_message_internal(&mut self, message_data: MessageType) -> Result<(), String> {
         match message_data {
             MessageType::Ping => {
-                let seralized = serde_json::to_string(&MessageType::Pong);
-                let _ = self.sink.send(Message::from(seralized.unwrap())).await;
+                self.sink.send(encode(MessageType::Pong));
                 Ok(())
             }
+            MessageType::SyncGame => {
+                let game = self.watched_game()?;
+                let sink = self.sink.clone();
+                game.call("SyncGame", move |actor| {
+                    if actor.executor.game.state.is_none() {
+                        return Err("The game has not started.".to_owned());
+                    }
+                    actor.step_to_now()?;
+                    let executor = &actor.executor;
+                    sink.send(encode(MessageType::GameClock(executor.clock())));
+                    sink.send(encode(MessageType::Game(executor.game.clone())));
+                    Ok(())
+                })
+                .await?
+            }
             MessageType::CreateGame(game_settings) => {
-                //let mut sink = self.sink.lock().unwrap();
+                let instance = self.instance.clone();
+                self.created_games
+                    .retain(|game_id| instance.game(game_id).is_some());
+                let max_games = self.instance.config.max_games_per_connection;
+                if self.created_games.len() >= max_games {
+                    return Err(format!(
+                        "You cannot have more than {} games open at once.",
+                        max_games
+                    ));
+                }
                 let game = {
                     let maps = self.instance.map_manager.lock().await;
                     let map = maps.map_by_id(&game_settings.map_id).ok_or_else(|| {
@@ -106,103 +252,17 @@ impl GameConnection {
                     })?;
                     Game::new((*map).clone(), game_settings.config)
                 };
-                self.instance.add_game(game).await;
+                let created = self.instance.add_game(game, game_settings.visibility)?;
+                self.created_games.push(created.game.game_id.clone());
+                self.sink.send(encode(MessageType::GameCreated(created)));
                 Ok(())
             }
             MessageType::ExitGame => {
-                let _ = self.sink.send(Message::from("ExitGame")).await;
-                Ok(())
-            }
-            MessageType::EnterGame(EnterGame {
-                game_id,
-                rejoin_code,
-            }) => {
-                let mut games = self.instance.games.write().await;
-                let game_executor_mtx = games.get_mut(&game_id).ok_or_else(|| {
-                    format!("Could not find a game with an id of \"{}\"", &game_id)
-                })?;
-                let mut game_executor = game_executor_mtx.lock().await;
-                let player = self
-                    .player
-                    .as_ref()
-                    .ok_or_else(|| "Players must set a name before joining a game.".to_owned())?;
-                let (game_player, rejoin_code) = if let Some(rejoin_code) = rejoin_code {
-                    let rejoin_mtx = self.instance.rejoin_codes.lock().await;
-                    let possession = rejoin_mtx
-                        .get(&(game_id.clone() + &rejoin_code))
-                        .ok_or_else(|| {
-                            format!(
-                                "Could not find a session with rejoin id of \"{}\"",
-                                &rejoin_code
-                            )
-                        })?;
-                    game_executor.remove_player(&Player {
-                        possession: *possession,
-                        ..player.clone()
-                    });
-                    (
-                        Player {
-                            possession: *possession,
-                            ..player.clone()
-                        },
-                        rejoin_code,
-                    )
-                } else {
-                    let rejoin_code = generate_rejoin_code();
-                    (
-                        Player {
-                            possession: 0,
-                            ..player.clone()
-                        },
-                        rejoin_code,
-                    )
-                };
-                self.player = Some(
-                    game_executor
-                        .add_player(game_player)
-                        .map_err(|_| "Too many players".to_owned())?,
-                );
-                let handler_sink = self.sink.clone();
-                // Subscribe to game state
-                game_executor.event_source.on_event(Box::new(
-                    move |event: &GameEvent, game: &mut Game| {
-                        GameConnection::handle_game_event(handler_sink.clone(), game, event);
-                    },
-                ));
-                let seralized = serde_json::to_string(&MessageType::EnterGame(EnterGame {
-                    game_id: game_id.clone(),
-                    rejoin_code: Some(rejoin_code.clone()),
-                }));
-                let _ = self.sink.send(Message::from(seralized.unwrap())).await;
-                if let Some(player) = &self.player {
-                    let mut rejoin_mtx = self.instance.rejoin_codes.lock().await;
-                    rejoin_mtx.insert(game_id.clone() + &rejoin_code, player.possession);
-                    let seralized =
-                        serde_json::to_string(&MessageType::Possession(player.possession as u32));
-                    let _ = self.sink.send(Message::from(seralized.unwrap())).await;
-                };
-                if game_executor.game.state.is_some() {
-                    // Send game state
-                    let time = game_executor.get_time();
-                    // Step to the latest time so the client can use it
-                    // to figure out the offset between it and the
-                    // server. This offset does not account for latency,
-                    // which will need to be fixed.
-                    game_executor.step_to(time);
-                    let seralized =
-                        serde_json::to_string(&MessageType::Game(game_executor.game.clone()));
-                    let _ = self.sink.send(Message::from(seralized.unwrap())).await;
-                } else {
-                    // Otherwise just send the player list
-                    let seralized = serde_json::to_string(&MessageType::GamePlayers(
-                        game_executor.game.players.clone(),
-                    ));
-                    let _ = self.sink.send(Message::from(seralized.unwrap())).await;
-                }
-
-                self.current_game = Some(game_executor_mtx.clone());
+                self.leave_game().await;
+                self.sink.send(encode(MessageType::ExitGame));
                 Ok(())
             }
+            MessageType::EnterGame(enter_game) => self.enter_game(enter_game).await,
             MessageType::SetName(name_data) => {
                 //Replace player to avoid mutexes/refcells and such
                 self.player = Some(if let Some(mut player) = self.player.clone() {
@@ -212,85 +272,404 @@ impl GameConnection {
                     Player {
                         name: name_data.name,
                         possession: 0, //Garbage data
+                        ready: false,
+                        host: false,
+                        disconnected: false,
+                        bot: false,
                     }
                 });
                 Ok(())
             }
             MessageType::StartGame => {
-                match self
-                    .current_game
-                    .as_ref()
-                    .ok_or_else(|| "Player is not currently in a game".to_owned())
-                {
-                    Ok(game_executor) => game_executor.lock().await.start_game(),
-                    Err(e) => Err(e),
-                }
+                let (game, possession) = self.joined_game()?;
+                game.call("StartGame", move |actor| {
+                    GameConnection::check_host(&actor.executor, possession, "start the game")?;
+                    actor.executor.start_game()?;
+                    actor.update_game();
+                    Ok(())
+                })
+                .await?
+            }
+            MessageType::SetReady(ready) => {
+                let (game, possession) = self.joined_game()?;
+                game.call("SetReady", move |actor| {
+                    actor.executor.set_ready(possession, ready)
+                })
+                .await?
+            }
+            MessageType::KickPlayer(kicked) => {
+                let (game, possession) = self.joined_game()?;
+                game.call("KickPlayer", move |actor| {
+                    GameConnection::check_host(&actor.executor, possession, "kick players")?;
+                    if kicked as usize == possession {
+                        return Err("The host cannot kick themselves.".to_owned());
+                    }
+                    let player = actor.executor.kick_player(kicked as usize)?;
+                    // Kicked players may not come back with their rejoin code
+                    actor.sessions.revoke_possession(player.possession);
+                    actor.update_game();
+                    Ok(())
+                })
+                .await?
+            }
+            MessageType::TransferHost(new_host) => {
+                let (game, possession) = self.joined_game()?;
+                game.call("TransferHost", move |actor| {
+                    GameConnection::check_host(&actor.executor, possession, "transfer hosting")?;
+                    actor.executor.set_host(new_host as usize)?;
+                    actor.update_game();
+                    Ok(())
+                })
+                .await?
+            }
+            MessageType::Surrender(Surrender { heir }) => {
+                let (game, possession) = self.joined_game()?;
+                let connection_id = self.connection_id;
+                game.call("Surrender", move |actor| {
+                    let surrendered = actor
+                        .executor
+                        .surrender(possession, heir.map(|heir| heir as usize));
+                    actor.check(surrendered)?;
+                    // The seat is gone, so there is nothing to rejoin
+                    actor.sessions.revoke_possession(possession);
+                    // Stay on to watch the rest of the game
+                    actor.room.spectators.insert(connection_id);
+                    if actor.executor.status() == GameStatus::Finished {
+                        actor.end_sessions();
+                    }
+                    actor.update_game();
+                    Ok::<(), String>(())
+                })
+                .await??;
+                self.session = None;
+                self.spectating = true;
+                Ok(())
+            }
+            MessageType::QueryGames(query) => {
+                let games = self.instance.list_games(&query);
+                self.sink.send(encode(MessageType::GameList(games)));
+                Ok(())
+            }
+            MessageType::UpdateGameSettings(game_settings) => {
+                let (game, possession) = self.joined_game()?;
+                let map = {
+                    let maps = self.instance.map_manager.lock().await;
+                    let map = maps.map_by_id(&game_settings.map_id).ok_or_else(|| {
+                        format!("Map with id \"{}\" not found.", game_settings.map_id)
+                    })?;
+                    (*map).clone()
+                };
+                game.call("UpdateGameSettings", move |actor| {
+                    GameConnection::check_host(
+                        &actor.executor,
+                        possession,
+                        "change the game settings",
+                    )?;
+                    actor.executor.update_settings(map, game_settings.config)?;
+                    actor.update_game();
+                    Ok(())
+                })
+                .await?
             }
             MessageType::GameMove(game_move) => {
-                let game_executor_mtx = self
-                    .current_game
-                    .as_ref()
-                    .ok_or_else(|| "Player is not currently in a game".to_owned());
-                match game_executor_mtx {
-                    Ok(game_exec_mtx) => {
-                        let mut game_executor = game_exec_mtx.lock().await;
-                        let timed_move = game_executor.create_move(game_move.from, game_move.to)?;
-                        game_executor.add_move(self.player.as_ref().unwrap(), timed_move)?;
+                let (game, _) = self.joined_game()?;
+                let player = self.player.clone().unwrap();
+                let received = Instant::now();
+                let played = game
+                    .call("GameMove", move |actor| {
+                        actor.play_move(&player, game_move.from, game_move.to)?;
+                        actor.record_activity(player.possession);
+                        if actor.executor.status() == GameStatus::Finished {
+                            actor.end_sessions();
+                            actor.update_game();
+                        }
                         Ok(())
+                    })
+                    .await;
+                let metrics = &self.instance.metrics;
+                metrics.move_latency.observe(received.elapsed());
+                played?
+            }
+            MessageType::Time(TimeSync {
+                client_send_time, ..
+            }) => {
+                // Allows the client to compute the offset between its clock
+                // and the server's, as well as the round trip latency.
+                let server_receive_time = get_millis();
+                self.sink.send(encode(MessageType::Time(TimeSync {
+                    client_send_time,
+                    server_receive_time,
+                    server_send_time: get_millis(),
+                })));
+                Ok(())
+            }
+            MessageType::SendChat(SendChat { scope, text }) => {
+                let text = validate_chat(&text)?;
+                let sender = self
+                    .player
+                    .as_ref()
+                    .ok_or_else(|| "Players must set a name before chatting.".to_owned())?
+                    .name
+                    .clone();
+                let game = match scope {
+                    ChatScope::Lobby => None,
+                    ChatScope::Game => Some(self.watched_game()?),
+                };
+                self.chat_limiter.check(get_millis())?;
+                let message = ChatMessage {
+                    scope,
+                    sender,
+                    text,
+                    time: get_millis(),
+                };
+                match game {
+                    Some(game) => {
+                        game.call("SendChat", move |actor| actor.post_chat(message))
+                            .await?
                     }
-                    Err(e) => Err(e),
+                    None => self.instance.post_lobby_chat(message).await,
                 }
-            }
-            MessageType::Time(_time) => {
-                // Allows the client to get the server's clock time so
-                // they can compute an offset between the server +
-                // latency and their own clock.
-                use std::time::SystemTime;
-                let time = SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                let _ = self.sink.send(Message::from(
-                    serde_json::to_string(&MessageType::Time(time)).unwrap(),
-                ));
                 Ok(())
             }
             _ => Err("The provided message type was not found.".to_owned()),
         }
     }
 
-    pub async fn handle_new_client(&mut self) {
-        let games = self.instance.games.read().await;
-        let map_manager = self.instance.map_manager.lock().await;
-        let message = &MessageType::MapList(map_manager.maps());
-        let seralized = serde_json::to_string(message);
-        let _ = self.sink.send(Message::from(seralized.unwrap())).await;
-        let games_metadata = stream::iter(games.iter())
-            .then(async move |(key, val)| {
-                let game_exec = val.lock().await;
-                GameMetadata {
-                    game_id: key.clone(),
-                    config: game_exec.game.config.clone(),
-                    map_id: game_exec.game.map.name.clone(),
+    async fn enter_game(&mut self, enter_game: EnterGame) -> Result<(), String> {
+        let EnterGame {
+            game_id,
+            rejoin_code,
+            password,
+            invite_code,
+            spectate,
+        } = enter_game;
+        let not_found = format!("Could not find a game with an id of \"{}\"", game_id);
+        let game = self
+            .instance
+            .game(&game_id)
+            .ok_or_else(|| not_found.clone())?;
+        let player = match (&self.player, spectate) {
+            (_, true) => None,
+            (Some(player), false) => Some(player.clone()),
+            (None, false) => {
+                return Err("Players must set a name before joining a game.".to_owned())
+            }
+        };
+        let current_game = self.watched_game().ok().map(|game| game.game_id);
+        if !self.spectating && current_game.as_ref() == Some(&game_id) {
+            if spectate {
+                return Err("You cannot spectate a game you are playing in.".to_owned());
+            }
+            return Err("You are already playing in this game.".to_owned());
+        }
+        // A connection is only ever in one game
+        self.leave_game().await;
+        let host = self.created_games.contains(&game_id);
+        let connection_id = self.connection_id;
+        let seated = Arc::new(AtomicBool::new(true));
+        let handler_seated = seated.clone();
+        let sink = self.sink.clone();
+        let seat = game
+            .call("EnterGame", move |actor| {
+                // Players rejoining have already been let in
+                let invited = invite_code.as_ref() == Some(&actor.room.invite_code);
+                if rejoin_code.is_none() && !invited {
+                    match &actor.room.access {
+                        Access::Public => (),
+                        // Don't reveal that the game exists
+                        Access::Unlisted => return Err(not_found),
+                        Access::Password(hash) => match password {
+                            Some(password) if hash.matches(&password) => (),
+                            Some(_) => return Err("Incorrect password.".to_owned()),
+                            None => return Err("This game requires a password.".to_owned()),
+                        },
+                    }
                 }
+                // Bring the game up to date before sending it
+                if actor.executor.game.state.is_some() {
+                    actor.step_to_now()?;
+                }
+                let seat = match player {
+                    Some(player) => Some(actor.take_seat(player, rejoin_code, host)?),
+                    None => {
+                        actor.room.spectators.insert(connection_id);
+                        actor.update_game();
+                        None
+                    }
+                };
+                let handler_seat = GameSeat {
+                    game_id: game_id.clone(),
+                    possession: seat.as_ref().map(|(player, _)| player.possession),
+                    seated: handler_seated,
+                };
+                let handler_sink = sink.clone();
+                // Subscribe to game state
+                let handler = actor.executor.event_source.on_event(Box::new(
+                    move |event: &GameEvent, game: &mut Game| {
+                        GameConnection::handle_game_event(
+                            &handler_sink,
+                            &handler_seat,
+                            game,
+                            event,
+                        );
+                    },
+                ));
+                let game_executor = &mut actor.executor;
+                let room = &actor.room;
+                sink.send(encode(MessageType::EnterGame(EnterGame {
+                    game_id,
+                    rejoin_code: seat.as_ref().map(|(_, rejoin_code)| rejoin_code.clone()),
+                    password: None,
+                    invite_code: None,
+                    spectate,
+                })));
+                // Players joining through an invite won't have seen the game in the list
+                sink.send(encode(MessageType::UpdateGame(game_metadata(
+                    game_executor,
+                    room,
+                ))));
+                if let Some((player, _)) = &seat {
+                    sink.send(encode(MessageType::Possession(player.possession as u32)));
+                };
+                if game_executor.game.state.is_some() {
+                    // Send the clock first so the client can place the game state in time
+                    sink.send(encode(MessageType::GameClock(game_executor.clock())));
+                    sink.send(encode(MessageType::Game(game_executor.game.clone())));
+                } else {
+                    // Otherwise just send the player list
+                    sink.send(encode(MessageType::GamePlayers(
+                        game_executor.game.players.clone(),
+                    )));
+                }
+                sink.send(encode(MessageType::ChatHistory(actor.room.chat.history())));
+                Ok((seat, handler))
             })
-            .collect()
-            .await;
-        let seralized = serde_json::to_string(&MessageType::GameList(GameList {
-            games: games_metadata,
-        }));
-        let _ = self.sink.send(Message::from(seralized.unwrap())).await;
+            .await??;
+        let (seat, handler) = seat;
+        if let Some((player, rejoin_code)) = seat {
+            self.player = Some(player);
+            self.session = Some((game.game_id.clone(), rejoin_code));
+        }
+        self.spectating = spectate;
+        self.seated = seated;
+        self.event_handler = Some(handler);
+        self.current_game = Some(game);
+        Ok(())
+    }
+
+    pub async fn handle_new_client(&mut self) {
+        let map_manager = self.instance.map_manager.lock().await;
+        let seralized = encode(MessageType::MapList(map_manager.maps()));
+        self.sink.send(seralized);
+        // The newest games, clients can page through the rest with QueryGames
+        let games = self.instance.list_games(&GameQuery::default());
+        self.sink.send(encode(MessageType::GameList(games)));
+        let history = self.instance.lobby_chat.lock().await.history();
+        self.sink.send(encode(MessageType::ChatHistory(history)));
+    }
+
+    /// The game the player is in and their possession in it. Once the
+    /// player has been kicked, they are no longer considered to be in it.
+    fn joined_game(&mut self) -> Result<(GameHandle, usize), String> {
+        let game = self.watched_game()?;
+        if self.spectating {
+            return Err("Spectators cannot take part in the game.".to_owned());
+        }
+        match &self.player {
+            Some(player) => Ok((game, player.possession)),
+            None => Err("Player is not currently in a game".to_owned()),
+        }
+    }
+
+    /// The game the connection is playing or spectating. The game is kept
+    /// after a kick until the connection leaves it, to unsubscribe from it.
+    fn watched_game(&self) -> Result<GameHandle, String> {
+        match &self.current_game {
+            Some(game) if self.seated.load(Ordering::SeqCst) => Ok(game.clone()),
+            _ => Err("Player is not currently in a game".to_owned()),
+        }
+    }
+
+    fn check_host(
+        game_executor: &GameExecutor,
+        possession: usize,
+        action: &str,
+    ) -> Result<(), String> {
+        if game_executor.host() == Some(possession) {
+            Ok(())
+        } else {
+            Err(format!("Only the host can {}.", action))
+        }
     }
 
     pub async fn handle_client_exit(&mut self) {
-        if let Some(game_executor_mtx) = &self.current_game {
-            let mut game_executor = game_executor_mtx.lock().await;
-            if let Some(player) = &self.player {
-                game_executor.remove_player(&player);
-            }
-            if game_executor.game.players.len() < 1 {
-                self.instance.remove_game(&game_executor.game_id).await;
+        if self.instance.is_shutting_down() {
+            // Keep the player's seat in the snapshot, so they can rejoin after the restart
+            if let (Some(game), Some(handler)) =
+                (self.current_game.take(), self.event_handler.take())
+            {
+                let _ = game
+                    .call("Unsubscribe", move |actor| {
+                        actor.executor.event_source.remove_handler(handler)
+                    })
+                    .await;
             }
+            return;
+        }
+        self.leave_game().await;
+    }
+
+    /// Takes the connection out of its current game. Players keep their seat
+    /// to rejoin if the game is running, and give it up otherwise.
+    async fn leave_game(&mut self) {
+        let session = self.session.take();
+        let handler = self.event_handler.take();
+        // Stop the game's events reaching the connection
+        let seated = self.seated.swap(false, Ordering::SeqCst);
+        let game = match self.current_game.take() {
+            Some(game) => game,
+            None => return,
         };
+        let spectating = mem::replace(&mut self.spectating, false);
+        let connection_id = self.connection_id;
+        let player = self.player.clone();
+        let lifetime = self.instance.config.session_lifetime_millis();
+        // The game may have been removed already
+        let _ = game
+            .call("Disconnect", move |actor| {
+                if let Some(handler) = handler {
+                    actor.executor.event_source.remove_handler(handler);
+                }
+                if !seated {
+                    // Kicked players have already lost their seat
+                    return;
+                }
+                if let Some((_, rejoin_code)) = session {
+                    actor
+                        .sessions
+                        .disconnect(&rejoin_code, get_millis(), lifetime);
+                }
+                if spectating {
+                    // Spectators don't keep a game alive
+                    actor.room.spectators.remove(&connection_id);
+                    actor.update_game();
+                    return;
+                }
+                if let Some(player) = &player {
+                    if actor.executor.game.state.is_some() {
+                        // Hold the seat in case the player comes back
+                        actor.disconnect_player(player.possession);
+                        actor.update_game();
+                        return;
+                    }
+                    actor.executor.remove_player(player);
+                }
+                if actor.executor.game.players.is_empty() {
+                    actor.remove();
+                } else {
+                    actor.update_game();
+                }
+            })
+            .await;
     }
 }
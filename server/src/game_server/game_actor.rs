@@ -0,0 +1,977 @@
+use super::access::Access;
+use super::chat::ChatLog;
+use super::persistence::{self, SavedGame};
+use super::rejoin::{generate_invite_code, Sessions};
+use super::{encode, game_metadata, GameServer};
+use futures::future;
+use ipg_core::game::crash::{CrashDump, GameInput};
+use ipg_core::game::{
+    bot, get_millis, GameEvent, GameExecutor, GameSnapshot, Player, SimulationError,
+};
+use ipg_core::protocol::messages::{
+    ChatMessage, GameMetadata, GameStatus, InviteCode, MessageType, RejoinCode,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{interval, interval_at, Instant, Interval};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Work for a game's task to do with the game, named after the request it is for.
+struct Command {
+    request: &'static str,
+    run: Box<dyn FnOnce(&mut GameActor) + Send>,
+}
+
+/// Most commands queued for a game before senders have to wait their turn
+const COMMAND_QUEUE_SIZE: usize = 64;
+/// How often held seats are checked, which is also how often bots move
+const SEAT_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// Most inputs recorded for a crash dump before the game is checkpointed again
+const MAX_RECORDED_INPUTS: usize = 600;
+/// Requests that change nothing a crash dump can't replay, either because
+/// they only read the game or because the moves they make are recorded.
+/// Any other request starts a new checkpoint.
+const REPLAYABLE_REQUESTS: &[&str] = &[
+    "GameMove",
+    "SyncGame",
+    "SendChat",
+    "GameDetails",
+    "Snapshot",
+    "Heartbeat",
+    "SeatCheck",
+    "Unsubscribe",
+];
+/// Requests that show a game waiting to start is still in use, holding off
+/// the lobby timeout
+const LOBBY_REQUESTS: &[&str] = &[
+    "EnterGame",
+    "SetReady",
+    "KickPlayer",
+    "TransferHost",
+    "UpdateGameSettings",
+    "SendChat",
+];
+
+/// A hosted game, along with the sessions players can rejoin it with. Each
+/// game runs on its own task, and everything else reaches it through a
+/// `GameHandle`, so games never wait on each other or on the lobby.
+///
+/// A panic or internal `SimulationError` while processing the game stops only
+/// this game. Its last checkpoint and the inputs since are written out as a
+/// `CrashDump`.
+pub struct GameActor {
+    pub executor: GameExecutor,
+    pub room: GameRoom,
+    pub sessions: Sessions,
+    server: Arc<GameServer>,
+    metadata: watch::Sender<GameMetadata>,
+    removed: bool,
+    /// When each disconnected player lost their connection
+    disconnected_since: HashMap<usize, u128>,
+    /// When each player last made a move, or came back to the game
+    last_activity: HashMap<usize, u128>,
+    /// When anyone last joined, readied up, changed the settings or chatted
+    /// before the game started
+    lobby_active_at: u128,
+    /// When the game was first seen to be over, in milliseconds since the unix epoch
+    finished_at: Option<u128>,
+    checkpoint: GameSnapshot,
+    /// Set when the game changed in a way its recorded inputs can't replay
+    checkpoint_due: bool,
+    inputs: Vec<GameInput>,
+}
+
+/// What the server keeps about a game besides the simulation, which
+/// clients never run themselves.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GameRoom {
+    /// Who may join the game. A game's visibility cannot be changed.
+    pub access: Access,
+    /// Lets anyone who has it join, whatever the visibility
+    pub invite_code: InviteCode,
+    /// When the game was created, in milliseconds since the unix epoch
+    pub created_at: u128,
+    pub chat: ChatLog,
+    /// Connections watching the game without playing. Nobody is connected
+    /// to a restored game, so this isn't saved.
+    #[serde(skip)]
+    pub spectators: HashSet<usize>,
+}
+
+impl GameRoom {
+    pub fn new(access: Access) -> Self {
+        GameRoom {
+            access,
+            invite_code: generate_invite_code(),
+            created_at: get_millis(),
+            chat: ChatLog::default(),
+            spectators: HashSet::new(),
+        }
+    }
+}
+
+/// Sends commands to a game's task.
+#[derive(Clone)]
+pub struct GameHandle {
+    pub game_id: String,
+    /// Whether the game appears in the lobby. A game's visibility cannot be changed.
+    pub listed: bool,
+    commands: mpsc::Sender<Command>,
+    metadata: watch::Receiver<GameMetadata>,
+}
+
+impl GameHandle {
+    /// Runs the command on the game's task, returning what it returns. The
+    /// request names the command in crash dumps.
+    pub async fn call<R, F>(&self, request: &'static str, command: F) -> Result<R, String>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut GameActor) -> R + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let command = Command {
+            request,
+            run: Box::new(move |actor| {
+                let _ = reply.send(command(actor));
+            }),
+        };
+        let ended = "The game has ended.";
+        self.commands
+            .clone()
+            .send(command)
+            .await
+            .map_err(|_| ended.to_owned())?;
+        result.await.map_err(|_| ended.to_owned())
+    }
+
+    /// What the lobby is told about the game, as of its last command.
+    pub fn metadata(&self) -> GameMetadata {
+        self.metadata.borrow().clone()
+    }
+}
+
+impl GameActor {
+    /// Starts running the game on its own task.
+    pub fn spawn(
+        server: Arc<GameServer>,
+        executor: GameExecutor,
+        room: GameRoom,
+        sessions: Sessions,
+    ) -> GameHandle {
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let (metadata, metadata_receiver) = watch::channel(game_metadata(&executor, &room));
+        let handle = GameHandle {
+            game_id: executor.game_id.clone(),
+            listed: room.access.is_listed(),
+            commands,
+            metadata: metadata_receiver,
+        };
+        // Players of a restored game get the grace period and idle timeout
+        // from the restart
+        let now = get_millis();
+        let last_activity = executor
+            .game
+            .players
+            .iter()
+            .filter(|player| !player.bot)
+            .map(|player| (player.possession, now))
+            .collect();
+        let disconnected_since = executor
+            .game
+            .players
+            .iter()
+            .filter(|player| player.disconnected && !player.bot)
+            .map(|player| (player.possession, now))
+            .collect();
+        let actor = GameActor {
+            checkpoint: executor.snapshot(),
+            checkpoint_due: false,
+            executor,
+            room,
+            sessions,
+            server,
+            metadata,
+            removed: false,
+            disconnected_since,
+            last_activity,
+            lobby_active_at: now,
+            finished_at: None,
+            inputs: Vec::new(),
+        };
+        // Games outlive the connection that created them
+        let span = info_span!(parent: None, "game", id = %handle.game_id);
+        tokio::spawn(actor.run(receiver).instrument(span));
+        handle
+    }
+
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        let config = &self.server.config;
+        let cleanup_interval = Duration::from_secs(config.cleanup_interval);
+        let heartbeat_interval = Duration::from_millis(config.heartbeat_interval);
+        let tick_length = Duration::from_millis(self.executor.tick_length as u64);
+        let mut ticks = None;
+        let mut heartbeats = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+        let mut seat_checks =
+            interval_at(Instant::now() + SEAT_CHECK_INTERVAL, SEAT_CHECK_INTERVAL);
+        let mut clean_ups = interval_at(Instant::now() + cleanup_interval, cleanup_interval);
+        while !self.removed {
+            // Only running games are ticked
+            if self.executor.status() != GameStatus::Running {
+                ticks = None;
+            } else if ticks.is_none() {
+                ticks = Some(interval(tick_length));
+            }
+            // Ticks are too frequent to publish the metadata after every one
+            let changed = tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command { request, run }) => {
+                        let _span = info_span!("command", request).entered();
+                        self.process(GameInput::Request(request.to_owned()), run);
+                        true
+                    }
+                    None => break,
+                },
+                _ = next_tick(&mut ticks) => self.tick(),
+                _ = heartbeats.tick() => {
+                    self.process(GameInput::Request("Heartbeat".to_owned()), GameActor::send_heartbeat);
+                    false
+                }
+                _ = seat_checks.tick() => {
+                    self.process(GameInput::Request("SeatCheck".to_owned()), GameActor::check_seats);
+                    true
+                }
+                _ = clean_ups.tick() => {
+                    self.process(GameInput::Request("CleanUp".to_owned()), GameActor::clean_up);
+                    true
+                }
+            };
+            if changed {
+                let _ = self
+                    .metadata
+                    .broadcast(game_metadata(&self.executor, &self.room));
+            }
+        }
+    }
+
+    /// Runs an input on the game, recording it for a crash dump. If it
+    /// panics, the game crashes and None is returned.
+    fn process<R>(&mut self, input: GameInput, run: impl FnOnce(&mut Self) -> R) -> Option<R> {
+        if let GameInput::Request(request) = &input {
+            self.checkpoint_due |= !REPLAYABLE_REQUESTS.contains(&request.as_str());
+            let waiting = self.executor.status() == GameStatus::Waiting;
+            if waiting && LOBBY_REQUESTS.contains(&request.as_str()) {
+                self.lobby_active_at = get_millis();
+            }
+        }
+        self.inputs.push(input);
+        match panic::catch_unwind(AssertUnwindSafe(|| run(self))) {
+            Ok(result) => {
+                if self.checkpoint_due || self.inputs.len() >= MAX_RECORDED_INPUTS {
+                    self.checkpoint = self.executor.snapshot();
+                    self.checkpoint_due = false;
+                    self.inputs.clear();
+                }
+                Some(result)
+            }
+            Err(panic) => {
+                self.crash(panic_message(&*panic));
+                None
+            }
+        }
+    }
+
+    /// Stops the game after a panic or internal error, telling its players
+    /// and writing a crash dump.
+    fn crash(&mut self, message: String) {
+        let game_id = self.executor.game_id.clone();
+        error!("Game crashed: {}", message);
+        let metrics = &self.server.metrics;
+        metrics.game_crashes.fetch_add(1, Ordering::Relaxed);
+        let dump = CrashDump {
+            game_id,
+            message,
+            tick: self
+                .executor
+                .game
+                .state
+                .as_ref()
+                .map_or(0, |galaxy| galaxy.time),
+            crashed_at: get_millis(),
+            checkpoint: self.checkpoint.clone(),
+            inputs: mem::take(&mut self.inputs),
+        };
+        match persistence::save_crash_dump(&self.server.config.crash_dump_dir, &dump) {
+            Ok(path) => info!("Saved a crash dump to {}", path.display()),
+            Err(error) => error!("Unable to save crash dump: {}", error),
+        }
+        self.executor.event_source.emit_event(
+            GameEvent::Crashed("The game crashed and had to be stopped.".to_owned()),
+            &mut self.executor.game,
+        );
+        self.remove();
+    }
+
+    /// Makes a move for the player, recording it so a crash can be replayed.
+    pub fn play_move(&mut self, player: &Player, from: u16, to: u16) -> Result<(), String> {
+        let time = self.executor.current_tick();
+        self.inputs.push(GameInput::Move {
+            possession: player.possession,
+            from,
+            to,
+            time,
+        });
+        let game_move = self.executor.create_move_at(from, to, time);
+        let game_move = self.check(game_move)?;
+        let added = self.executor.add_move(player, game_move);
+        self.check(added)
+    }
+
+    /// Steps the game up to the current time.
+    pub fn step_to_now(&mut self) -> Result<(), String> {
+        let time = self.executor.current_tick();
+        let stepped = self.executor.step_to(time);
+        self.check(stepped)
+    }
+
+    /// Turns a simulation error into a reply for the client. Errors that
+    /// leave the galaxy inconsistent crash the game.
+    pub fn check<T>(&mut self, result: Result<T, SimulationError>) -> Result<T, String> {
+        result.map_err(|error| {
+            if error.is_internal() {
+                self.crash(error.to_string());
+            }
+            error.to_string()
+        })
+    }
+
+    /// Tells every connection about a change to a listed game, such as its
+    /// settings, players or status. Only the players of unlisted games are told.
+    pub fn update_game(&mut self) {
+        let metadata = game_metadata(&self.executor, &self.room);
+        if self.room.access.is_listed() {
+            self.server
+                .broadcast(encode(MessageType::UpdateGame(metadata)));
+        } else {
+            self.executor
+                .event_source
+                .emit_event(GameEvent::Metadata(metadata), &mut self.executor.game);
+        }
+    }
+
+    /// Stops hosting the game.
+    pub fn remove(&mut self) {
+        self.removed = true;
+        self.end_sessions();
+        self.server
+            .forget_game(&self.executor.game_id, self.room.access.is_listed());
+    }
+
+    /// Revokes every rejoin session for a game that is over.
+    pub fn end_sessions(&mut self) {
+        self.sessions = Sessions::default();
+    }
+
+    /// The game and its rejoin sessions, to bring back after a restart.
+    /// Expired sessions are dropped along the way.
+    pub fn snapshot(&mut self) -> SavedGame {
+        self.sessions.remove_expired(get_millis());
+        SavedGame {
+            game: self.executor.snapshot(),
+            room: self.room.clone(),
+            sessions: self.sessions.clone(),
+        }
+    }
+
+    /// Sends a chat message to everyone in the game, keeping it for players who join later.
+    pub fn post_chat(&mut self, message: ChatMessage) {
+        self.room.chat.push(message.clone());
+        self.executor.post_chat(message);
+    }
+
+    /// Holds a player's seat after they lose their connection, until they
+    /// rejoin or the grace period runs out.
+    pub fn disconnect_player(&mut self, possession: usize) {
+        self.executor.disconnect_player(possession);
+        self.disconnected_since.insert(possession, get_millis());
+    }
+
+    /// Notes that a player is still playing. A player who a bot took over
+    /// for gets control back.
+    pub fn record_activity(&mut self, possession: usize) {
+        self.last_activity.insert(possession, get_millis());
+        self.executor.resume_control(possession);
+    }
+
+    /// Connected players in a running game who haven't made a move for
+    /// longer than the idle timeout.
+    fn idle_players(&self, now: u128) -> Vec<usize> {
+        let timeout = self.server.config.idle_timeout_millis();
+        let start_time = self.executor.start_time;
+        self.executor
+            .game
+            .players
+            .iter()
+            .filter(|player| !player.bot && !player.disconnected)
+            .filter(|player| {
+                let last_active = self
+                    .last_activity
+                    .get(&player.possession)
+                    .map_or(start_time, |time| (*time).max(start_time));
+                now >= last_active + timeout
+            })
+            .map(|player| player.possession)
+            .collect()
+    }
+
+    /// Players who have been disconnected for longer than the grace period.
+    fn overdue_players(&mut self, now: u128) -> Vec<usize> {
+        let grace_period = self.server.config.disconnect_grace_period_millis();
+        let players = &self.executor.game.players;
+        // Players who came back or left the game are no longer waited for
+        self.disconnected_since.retain(|possession, _| {
+            players
+                .iter()
+                .any(|player| player.possession == *possession && player.disconnected)
+        });
+        self.disconnected_since
+            .iter()
+            .filter(|(_, since)| now >= *since + grace_period)
+            .map(|(possession, _)| *possession)
+            .collect()
+    }
+
+    /// Adds the player to the game, either in the possession their rejoin
+    /// session was issued for or a new one. Returns the seated player and
+    /// the session's token.
+    pub fn take_seat(
+        &mut self,
+        player: Player,
+        rejoin_code: Option<RejoinCode>,
+        host: bool,
+    ) -> Result<(Player, RejoinCode), String> {
+        let now = get_millis();
+        let lifetime = self.server.config.session_lifetime_millis();
+        let seated = if let Some(rejoin_code) = &rejoin_code {
+            let session = self.sessions.resume(rejoin_code, now, lifetime)?;
+            // A held seat is given back as it was, so the host stays the host
+            match self.executor.reconnect_player(session.possession) {
+                Some(player) => Ok(player),
+                // The seat was given up, e.g. by leaving a game before it started
+                None => self.executor.add_player(Player {
+                    possession: session.possession,
+                    name: session.name,
+                    ..player
+                }),
+            }
+        } else {
+            self.executor.add_player(Player {
+                possession: 0,
+                ..player
+            })
+        };
+        let player = match seated {
+            Ok(player) => player,
+            Err(_) => {
+                if let Some(rejoin_code) = &rejoin_code {
+                    self.sessions.disconnect(rejoin_code, now, lifetime);
+                }
+                return Err("Too many players".to_owned());
+            }
+        };
+        let possession = player.possession;
+        let rejoin_code = match rejoin_code {
+            Some(rejoin_code) => rejoin_code,
+            None => self.sessions.issue(possession, &player.name, now, lifetime),
+        };
+        self.record_activity(possession);
+        // Players host the games they create
+        if host && self.executor.game.state.is_none() && self.executor.host() != Some(possession) {
+            self.executor.set_host(possession)?;
+        }
+        self.update_game();
+        Ok((player, rejoin_code))
+    }
+
+    /// Steps a running game up to the current time, so captures and
+    /// eliminations are announced as they happen rather than when someone
+    /// next makes a move. Returns whether the game ended.
+    fn tick(&mut self) -> bool {
+        if self.executor.status() != GameStatus::Running {
+            return false;
+        }
+        let time = self.executor.current_tick();
+        let stepped = self.process(GameInput::Step(time), |actor| {
+            let stepped = actor.executor.step_to(time);
+            actor.check(stepped).is_ok()
+        });
+        if stepped != Some(true) || self.executor.status() != GameStatus::Finished {
+            return false;
+        }
+        self.end_sessions();
+        self.update_game();
+        true
+    }
+
+    fn send_heartbeat(&mut self) {
+        if self.executor.status() != GameStatus::Running {
+            return;
+        }
+        if let Some(heartbeat) = self.executor.heartbeat() {
+            self.executor
+                .event_source
+                .emit_event(GameEvent::Heartbeat(heartbeat), &mut self.executor.game);
+        }
+    }
+
+    /// Releases the seats of players who have been disconnected for too long,
+    /// lets bots take their turns, and removes the game once nobody is
+    /// playing it anymore.
+    fn check_seats(&mut self) {
+        let overdue = self.overdue_players(get_millis());
+        let changed = self.release_seats(overdue);
+        if self.removed {
+            return;
+        }
+        if self.executor.status() == GameStatus::Running {
+            self.play_bots();
+        }
+        // Games that haven't started are removed when their last player leaves
+        let started = self.executor.game.state.is_some();
+        if started && !self.executor.has_human_players() {
+            self.remove();
+        } else if changed {
+            self.update_game();
+        }
+    }
+
+    /// Removes the game if its lobby has gone quiet, it has been over for a
+    /// while, or all its players have gone idle. Idle players lose their seats the same
+    /// way disconnected players do.
+    fn clean_up(&mut self) {
+        let now = get_millis();
+        let server = self.server.clone();
+        let config = &server.config;
+        let expired = match self.executor.status() {
+            GameStatus::Waiting => now >= self.lobby_active_at + config.lobby_timeout_millis(),
+            GameStatus::Finished => {
+                self.executor.record_winner();
+                let finished_at = *self.finished_at.get_or_insert(now);
+                now >= finished_at + config.finished_game_timeout_millis()
+            }
+            GameStatus::Running => {
+                let idle = self.idle_players(now);
+                if self.release_seats(idle) && self.executor.has_human_players() {
+                    self.update_game();
+                }
+                !self.executor.has_human_players()
+            }
+        };
+        if expired && !self.removed {
+            info!("Removing abandoned game.");
+            self.remove();
+        }
+    }
+
+    /// Gives up the players' seats as the game's disconnect policy says.
+    /// Returns whether any seats were released.
+    fn release_seats(&mut self, possessions: Vec<usize>) -> bool {
+        let changed = !possessions.is_empty();
+        // Seats given up aren't among the inputs a crash dump replays
+        self.checkpoint_due |= changed;
+        for possession in possessions {
+            let released = self.executor.release_seat(possession);
+            match self.check(released) {
+                // The seat is gone, so there is nothing left to rejoin
+                Ok(true) => self.sessions.revoke_possession(possession),
+                Ok(false) => (),
+                Err(_) => break,
+            }
+        }
+        changed
+    }
+
+    fn play_bots(&mut self) {
+        let bots: Vec<Player> = self
+            .executor
+            .game
+            .players
+            .iter()
+            .filter(|player| player.bot)
+            .cloned()
+            .collect();
+        for player in bots {
+            if self.removed || self.step_to_now().is_err() {
+                return;
+            }
+            let planets = self
+                .executor
+                .game
+                .state
+                .as_ref()
+                .and_then(|galaxy| bot::choose_move(galaxy, player.possession));
+            if let Some((from, to)) = planets {
+                if let Err(error) = self.play_move(&player, from, to) {
+                    warn!(possession = player.possession, "Bot move failed: {}", error);
+                }
+            }
+        }
+    }
+}
+
+/// Waits for the next tick, or forever if the game isn't being ticked.
+async fn next_tick(ticks: &mut Option<Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::game_server::connection::GameConnection;
+    use crate::game_server::map_manager::{FileSystemMapManager, MapManager};
+    use crate::game_server::outbox::{Outbox, OutboxStats};
+    use crate::game_server::tests::running_game;
+    use ipg_core::game::{DisconnectPolicy, Game, GameConfig, Planet, TICK_LENGTH};
+    use ipg_core::protocol::messages::{EnterGame, Envelope, SetName, Visibility};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::AtomicBool;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn test_server(config: ServerConfig) -> Arc<GameServer> {
+        let maps = FileSystemMapManager::new("../maps".to_owned());
+        Arc::new(GameServer::new(config, maps))
+    }
+
+    fn start_game(server: &Arc<GameServer>, game_id: &str) -> GameHandle {
+        let room = GameRoom::new(Access::Public);
+        GameActor::spawn(
+            server.clone(),
+            running_game(game_id),
+            room,
+            Sessions::default(),
+        )
+    }
+
+    /// Spawns a game nobody has started, with one player waiting in it.
+    fn open_lobby(server: &Arc<GameServer>, game_id: &str) -> GameHandle {
+        let maps = FileSystemMapManager::new("../maps".to_owned());
+        let map = maps.map_by_id(&"Trio".to_owned()).unwrap().clone();
+        let config = GameConfig {
+            min_players: 2,
+            disconnect_policy: DisconnectPolicy::Forfeit,
+        };
+        let mut executor = GameExecutor::from_game(Game::new(map, config), game_id.to_owned());
+        executor
+            .add_player(Player {
+                name: "Ada".to_owned(),
+                possession: 0,
+                ready: false,
+                host: false,
+                disconnected: false,
+                bot: false,
+            })
+            .unwrap();
+        let room = GameRoom::new(Access::Public);
+        GameActor::spawn(server.clone(), executor, room, Sessions::default())
+    }
+
+    /// Sends the connection a request, as its client would.
+    async fn request(connection: &mut GameConnection, message: MessageType) {
+        let envelope = Envelope::from(message);
+        let frame = Message::from(serde_json::to_string(&envelope).unwrap());
+        connection.handle_message(&frame).await;
+    }
+
+    /// Enters the game as a player with the name, or as a spectator.
+    async fn enter(connection: &mut GameConnection, game_id: &str, spectate: bool) {
+        let enter_game = EnterGame {
+            game_id: game_id.to_owned(),
+            rejoin_code: None,
+            password: None,
+            invite_code: None,
+            spectate,
+        };
+        request(connection, MessageType::EnterGame(enter_game)).await;
+    }
+
+    /// Sends ships from the first player's planet to one they don't own.
+    fn make_move(actor: &mut GameActor) -> Result<(), String> {
+        let player = actor.executor.game.players[0].clone();
+        let galaxy = actor.executor.game.state.as_ref().unwrap();
+        let owned = |planet: &&Planet| planet.possession == Some(player.possession);
+        let from = galaxy.planets.iter().find(owned).unwrap().index as u16;
+        let to = galaxy
+            .planets
+            .iter()
+            .find(|planet| !owned(planet))
+            .unwrap()
+            .index as u16;
+        actor.play_move(&player, from, to)
+    }
+
+    #[tokio::test]
+    async fn commands_see_earlier_commands() {
+        let server = test_server(ServerConfig::default());
+        let game = start_game(&server, "game");
+        let released = game.call("Forfeit", |actor| actor.release_seats(vec![1]));
+        assert!(released.await.unwrap());
+        let players = game.call("GameDetails", |actor| actor.executor.game.players.len());
+        assert_eq!(players.await.unwrap(), 1);
+        // The lobby's view is updated once a command is done
+        assert_eq!(game.metadata().players, 1);
+        game.call("Remove", GameActor::remove).await.unwrap();
+        let ended = game.call("GameDetails", |_| ()).await;
+        assert_eq!(ended, Err("The game has ended.".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn running_games_are_ticked() {
+        let server = test_server(ServerConfig::default());
+        let game = start_game(&server, "game");
+        let tick_length = Duration::from_millis(TICK_LENGTH as u64);
+        tokio::time::delay_for(tick_length * 3).await;
+        // Nothing asked the game to step, but it caught up with its clock,
+        // which started two seconds ago
+        let time = game.call("GameDetails", |actor| {
+            actor.executor.game.state.as_ref().unwrap().time
+        });
+        assert!(time.await.unwrap() >= 2000 / TICK_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn crashes_only_stop_their_game() {
+        let crash_dump_dir = env::temp_dir().join(format!("ipg-crashes-{}", process::id()));
+        let config = ServerConfig {
+            crash_dump_dir: crash_dump_dir.to_str().unwrap().to_owned(),
+            ..ServerConfig::default()
+        };
+        let server = test_server(config);
+        let crashing = start_game(&server, "crashing");
+        let other = start_game(&server, "other");
+
+        crashing.call("GameMove", make_move).await.unwrap().unwrap();
+        assert!(crashing.call("Crash", |_| panic!("Boom")).await.is_err());
+        // Commands are handled in order, so the dump has been written by now
+        assert!(crashing.call("SyncGame", |_| ()).await.is_err());
+        let status = other
+            .call("SyncGame", |actor| actor.executor.status())
+            .await;
+        assert!(matches!(status, Ok(GameStatus::Running)));
+        assert_eq!(server.metrics.game_crashes.load(Ordering::Relaxed), 1);
+
+        let path = fs::read_dir(&crash_dump_dir).unwrap().next().unwrap();
+        let contents = fs::read_to_string(path.unwrap().path()).unwrap();
+        fs::remove_dir_all(&crash_dump_dir).unwrap();
+        let dump: CrashDump = serde_json::from_str(&contents).unwrap();
+        assert_eq!(dump.game_id, "crashing");
+        assert_eq!(dump.message, "Boom");
+        assert!(
+            matches!(dump.offending_input(), Some(GameInput::Request(request)) if request == "Crash")
+        );
+        // Moves don't start a new checkpoint, so the move is replayed
+        let moved = dump
+            .inputs
+            .iter()
+            .any(|input| matches!(input, GameInput::Move { .. }));
+        assert!(moved);
+        let (replayed, skipped) = dump.replay();
+        assert_eq!(replayed.game_id, "crashing");
+        assert!(skipped
+            .iter()
+            .all(|reason| reason.contains("requests can't be replayed")));
+    }
+
+    #[tokio::test]
+    async fn disconnected_players_keep_their_seats_for_the_grace_period() {
+        let server = test_server(ServerConfig::default());
+        let game = start_game(&server, "game");
+        let grace_period = server.config.disconnect_grace_period_millis();
+        let seats = |actor: &mut GameActor| {
+            actor.check_seats();
+            let players = actor.executor.game.players.iter();
+            players.map(|player| player.possession).collect::<Vec<_>>()
+        };
+        let backdate = |millis: u128| {
+            move |actor: &mut GameActor| {
+                for since in actor.disconnected_since.values_mut() {
+                    *since -= millis;
+                }
+            }
+        };
+
+        let disconnected = game.call("Disconnect", |actor| actor.disconnect_player(1));
+        disconnected.await.unwrap();
+        assert_eq!(game.call("SeatCheck", seats).await.unwrap(), vec![0, 1]);
+        // A second short of the grace period, the seat is still held
+        game.call("Backdate", backdate(grace_period - 1000))
+            .await
+            .unwrap();
+        assert_eq!(game.call("SeatCheck", seats).await.unwrap(), vec![0, 1]);
+        game.call("Backdate", backdate(1000)).await.unwrap();
+        assert_eq!(game.call("SeatCheck", seats).await.unwrap(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn idle_lobbies_are_removed_after_the_lobby_timeout() {
+        let server = test_server(ServerConfig::default());
+        let game = open_lobby(&server, "lobby");
+        let timeout = server.config.lobby_timeout_millis();
+        let clean_up_after = |millis: u128| {
+            move |actor: &mut GameActor| {
+                actor.lobby_active_at -= millis;
+                actor.clean_up();
+            }
+        };
+
+        // Lobbies in use stay open however old they are
+        let age = move |actor: &mut GameActor| actor.room.created_at -= 2 * timeout;
+        game.call("Backdate", age).await.unwrap();
+        let ready = game.call("SetReady", |actor| actor.executor.set_ready(0, true));
+        ready.await.unwrap().unwrap();
+        // A second short of the timeout, the lobby is still open
+        game.call("CleanUp", clean_up_after(timeout - 1000))
+            .await
+            .unwrap();
+        assert!(game.call("GameDetails", |_| ()).await.is_ok());
+        game.call("CleanUp", clean_up_after(1000)).await.unwrap();
+        assert!(game.call("GameDetails", |_| ()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn finished_games_are_removed_after_their_timeout() {
+        let server = test_server(ServerConfig::default());
+        let game = start_game(&server, "game");
+        let timeout = server.config.finished_game_timeout_millis();
+        let clean_up_after = |millis: u128| {
+            move |actor: &mut GameActor| {
+                actor.finished_at = actor.finished_at.map(|finished_at| finished_at - millis);
+                actor.clean_up();
+            }
+        };
+
+        let surrendered = game.call("Surrender", |actor| actor.executor.surrender(1, Some(0)));
+        assert!(surrendered.await.unwrap().is_ok());
+        // The timeout starts when the janitor first sees the game is over
+        let status = game.call("CleanUp", |actor| {
+            actor.clean_up();
+            actor.executor.status()
+        });
+        assert!(matches!(status.await, Ok(GameStatus::Finished)));
+        game.call("CleanUp", clean_up_after(timeout - 1000))
+            .await
+            .unwrap();
+        assert!(game.call("GameDetails", |_| ()).await.is_ok());
+        game.call("CleanUp", clean_up_after(1000)).await.unwrap();
+        assert!(game.call("GameDetails", |_| ()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn leaving_a_game_unsubscribes_from_its_events() {
+        let config = ServerConfig {
+            messages_per_second: 100,
+            ..ServerConfig::default()
+        };
+        let server = test_server(config);
+        let maps = FileSystemMapManager::new("../maps".to_owned());
+        let map = maps.map_by_id(&"Trio".to_owned()).unwrap().clone();
+        let config = GameConfig {
+            min_players: 2,
+            disconnect_policy: DisconnectPolicy::Forfeit,
+        };
+        let created = server.add_game(Game::new(map, config), Visibility::Public);
+        let game_id = created.unwrap().game.game_id;
+        let game = server.game(&game_id).unwrap();
+        let handlers = || {
+            game.call("GameDetails", |actor| {
+                actor.executor.event_source.handlers.len()
+            })
+        };
+        let connect = |connection_id, name: &str| {
+            let outbox = Outbox::new(1024, Arc::new(OutboxStats::default()));
+            let connection = GameConnection::new(server.clone(), connection_id, outbox);
+            let name = MessageType::SetName(SetName {
+                name: name.to_owned(),
+            });
+            (connection, name)
+        };
+        // The host keeps the lobby open while the guest comes and goes
+        let (mut host, name) = connect(0, "Ada");
+        request(&mut host, name).await;
+        enter(&mut host, &game_id, false).await;
+        let (mut guest, name) = connect(1, "Grace");
+        request(&mut guest, name).await;
+
+        for _ in 0..3 {
+            enter(&mut guest, &game_id, false).await;
+            request(&mut guest, MessageType::ExitGame).await;
+            enter(&mut guest, &game_id, true).await;
+            enter(&mut guest, &game_id, false).await;
+            assert_eq!(handlers().await.unwrap(), 2);
+            request(&mut host, MessageType::KickPlayer(1)).await;
+            request(&mut guest, MessageType::ExitGame).await;
+            assert_eq!(handlers().await.unwrap(), 1);
+        }
+        enter(&mut guest, &game_id, true).await;
+        guest.handle_client_exit().await;
+        assert_eq!(handlers().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn hosts_rejoin_as_the_host() {
+        let server = test_server(ServerConfig::default());
+        let game = start_game(&server, "game");
+        let rejoined = game.call("EnterGame", |actor| {
+            let ada = actor.executor.game.players[0].clone();
+            assert!(ada.host);
+            let now = get_millis();
+            let rejoin_code = actor.sessions.issue(ada.possession, &ada.name, now, 1000);
+            actor.sessions.disconnect(&rejoin_code, now, 1000);
+            actor.disconnect_player(ada.possession);
+            let left = Arc::new(AtomicBool::new(false));
+            let leave = left.clone();
+            actor.executor.event_source.on_event(Box::new(
+                move |event: &GameEvent, _: &mut Game| {
+                    if matches!(event, GameEvent::PlayerLeave(_) | GameEvent::Player(_)) {
+                        leave.store(true, Ordering::SeqCst);
+                    }
+                },
+            ));
+            let (player, _) = actor.take_seat(ada, Some(rejoin_code), false).unwrap();
+            (
+                player,
+                actor.executor.game.players.clone(),
+                left.load(Ordering::SeqCst),
+            )
+        });
+        let (player, players, left) = rejoined.await.unwrap();
+        assert!(player.host && !player.disconnected);
+        assert_eq!(players.len(), 2);
+        assert!(players[0].host && !players[1].host);
+        // Others only see the player come back, not leave and join again
+        assert!(!left);
+    }
+}
@@ -0,0 +1,128 @@
+//! Just enough HTTP/1.1 to answer simple GET requests, such as metrics scrapes.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Largest request head accepted, in bytes
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+/// Most headers accepted in a request
+const MAX_HEADERS: usize = 32;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Whether the client asked to switch to websocket
+    pub upgrade: bool,
+    /// Everything read from the stream while reading the head
+    pub received: Vec<u8>,
+}
+
+/// Reads the head of a request, ignoring any body.
+pub async fn read_request(stream: &mut TcpStream) -> Result<Request, String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|error| error.to_string())?;
+        if read == 0 {
+            return Err("The connection closed before the request was complete.".to_owned());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buffer) {
+            Ok(httparse::Status::Complete(_)) => {
+                let upgrade = request.headers.iter().any(|header| {
+                    header.name.eq_ignore_ascii_case("upgrade")
+                        && header.value.eq_ignore_ascii_case(b"websocket")
+                });
+                return Ok(Request {
+                    method: request.method.unwrap_or_default().to_owned(),
+                    path: request.path.unwrap_or_default().to_owned(),
+                    upgrade,
+                    received: buffer,
+                });
+            }
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_HEAD_SIZE => (),
+            Ok(httparse::Status::Partial) => return Err("The request is too large.".to_owned()),
+            Err(error) => return Err(error.to_string()),
+        }
+    }
+}
+
+/// Sends a response and closes the connection.
+pub async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// A stream that gives back what was already read from it before reading
+/// any more, so a request can be looked at and still be handed on whole.
+pub struct Replay<S> {
+    received: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Replay<S> {
+    pub fn new(received: Vec<u8>, inner: S) -> Self {
+        Replay {
+            received,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buffer: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let replay = &mut *self;
+        let remaining = &replay.received[replay.position..];
+        if remaining.is_empty() {
+            return Pin::new(&mut replay.inner).poll_read(cx, buffer);
+        }
+        let count = remaining.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&remaining[..count]);
+        replay.position += count;
+        Poll::Ready(Ok(count))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buffer)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
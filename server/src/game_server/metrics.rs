@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the move latency histogram's buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Counts of something, by protocol message kind.
+#[derive(Default)]
+pub struct KindCounter {
+    counts: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl KindCounter {
+    pub fn add(&self, kind: &'static str) {
+        *self.counts.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        let counts = self.counts.lock().unwrap();
+        counts.iter().map(|(kind, count)| (*kind, *count)).collect()
+    }
+}
+
+/// Distribution of durations, in the buckets of `LATENCY_BUCKETS`.
+pub struct Histogram {
+    /// Observations at or below each bucket's bound
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// Sum of the observations in microseconds
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// What the server has done since it started, for operators to scrape.
+#[derive(Default)]
+pub struct Metrics {
+    pub messages_received: KindCounter,
+    pub messages_sent: KindCounter,
+    /// Requests answered with an error, by the kind of request
+    pub request_errors: KindCounter,
+    /// Messages from clients that couldn't be parsed
+    pub invalid_messages: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    /// How long moves take from reaching the server to being played
+    pub move_latency: Histogram,
+    pub game_crashes: AtomicU64,
+}
+
+/// Builds a response in the Prometheus text format.
+#[derive(Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl Into<f64>) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.text, "{} {}", name, value.into());
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.text, "{} {}", name, value);
+    }
+
+    /// A metric with one sample per value of a label.
+    pub fn labelled(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        label: &str,
+        samples: &[(&str, u64)],
+    ) {
+        self.header(name, kind, help);
+        for (value, sample) in samples {
+            let _ = writeln!(self.text, "{}{{{}=\"{}\"}} {}", name, label, value, sample);
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "histogram", help);
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            let count = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(self.text, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(self.text, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(self.text, "{}_sum {}", name, sum);
+        let _ = writeln!(self.text, "{}_count {}", name, count);
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+impl Metrics {
+    /// Adds the counters kept here to the exposition.
+    pub fn expose(&self, exposition: &mut Exposition) {
+        exposition.labelled(
+            "ipg_messages_received_total",
+            "counter",
+            "Messages received from clients.",
+            "kind",
+            &self.messages_received.snapshot(),
+        );
+        exposition.labelled(
+            "ipg_messages_sent_total",
+            "counter",
+            "Messages sent to clients.",
+            "kind",
+            &self.messages_sent.snapshot(),
+        );
+        exposition.labelled(
+            "ipg_request_errors_total",
+            "counter",
+            "Requests answered with an error.",
+            "kind",
+            &self.request_errors.snapshot(),
+        );
+        exposition.counter(
+            "ipg_invalid_messages_total",
+            "Messages from clients that could not be parsed.",
+            self.invalid_messages.load(Ordering::Relaxed),
+        );
+        exposition.counter(
+            "ipg_bytes_sent_total",
+            "Bytes of messages sent to clients.",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+        exposition.histogram(
+            "ipg_move_duration_seconds",
+            "Time taken to play a move, from receiving it to replying.",
+            &self.move_latency,
+        );
+        exposition.counter(
+            "ipg_game_crashes_total",
+            "Games stopped after a bug.",
+            self.game_crashes.load(Ordering::Relaxed),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(300));
+        histogram.observe(Duration::from_millis(30));
+        let mut exposition = Exposition::default();
+        exposition.histogram("latency", "Latency.", &histogram);
+        let text = exposition.finish();
+        assert!(text.contains("latency_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("latency_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("latency_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("latency_count 2\n"));
+    }
+}
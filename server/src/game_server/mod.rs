@@ -1,157 +1,703 @@
 use futures::{SinkExt, StreamExt};
-use ipg_core::game::Game;
-use ipg_core::protocol::messages::{GameMetadata, MessageType, RejoinCode};
+use ipg_core::game::map::MapSize;
+use ipg_core::game::{Game, Player};
+use ipg_core::protocol::messages::{
+    ChatMessage, Envelope, GameCreated, GameList, GameMetadata, GameQuery, GameStatus, MessageType,
+    Visibility,
+};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use serde_derive::Serialize;
+use serde_json::json;
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::future::Future;
 use std::iter;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio::{
     net::{TcpListener, TcpStream},
     runtime::Runtime,
-    sync::{mpsc, Mutex, RwLock},
+    sync::Mutex,
+};
+use tokio_tungstenite::accept_async_with_config;
+use tokio_tungstenite::tungstenite::protocol::{
+    frame::coding::CloseCode, CloseFrame, WebSocketConfig,
 };
-use tokio_tungstenite::accept_async;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
+use tracing::{error, info, info_span, warn, Instrument};
+pub mod access;
+pub mod chat;
 pub mod connection;
+pub mod game_actor;
+pub mod http;
 pub mod map_manager;
+pub mod metrics;
+pub mod outbox;
+pub mod persistence;
+pub mod rate_limit;
 pub mod rejoin;
+#[cfg(test)]
+mod tests;
+use self::access::Access;
+use self::chat::ChatLog;
 use self::connection::GameConnection;
+use self::game_actor::{GameActor, GameHandle, GameRoom};
+use self::metrics::{Exposition, Metrics};
+use self::outbox::{Outbox, OutboxStats, Outgoing};
+use self::persistence::ServerSnapshot;
+use self::rejoin::Sessions;
+use crate::config::ServerConfig;
 
 use ipg_core::game::GameExecutor;
 
 pub struct GameServer {
-    #[allow(unused)]
-    port: u16,
-    games: RwLock<HashMap<String, Arc<Mutex<GameExecutor>>>>,
-    // In theory, the sinks will end up all being the same type, meaning static dispatch is not out of the quesiton.
-    connections: Mutex<Vec<mpsc::Sender<Message>>>,
-    rejoin_codes: Mutex<HashMap<RejoinCode, usize>>,
+    config: ServerConfig,
+    /// Neither map is ever locked for longer than it takes to look something up
+    games: RwLock<HashMap<String, GameHandle>>,
+    connections: std::sync::Mutex<HashMap<usize, Outbox>>,
+    next_connection_id: AtomicUsize,
+    /// Connections accepted that haven't finished the websocket handshake yet
+    pending_handshakes: AtomicUsize,
+    /// Handshakes that failed or timed out since the server started
+    failed_handshakes: AtomicUsize,
+    outbox_stats: Arc<OutboxStats>,
+    metrics: Metrics,
     map_manager: Mutex<Box<dyn map_manager::MapManager + Send>>,
+    lobby_chat: Mutex<ChatLog>,
+    shutting_down: AtomicBool,
 }
 
-trait GameList {
-    fn add_game(&mut self, game: Game) -> String;
+/// Serializes a protocol message into a websocket frame for a client's outbox.
+pub fn encode(envelope: impl Into<Envelope>) -> Outgoing {
+    let envelope = envelope.into();
+    let frame = Message::from(serde_json::to_string(&envelope).unwrap());
+    Outgoing::new(&envelope, frame)
 }
 
-impl GameList for HashMap<String, Arc<Mutex<GameExecutor>>> {
-    fn add_game(&mut self, game: Game) -> String {
-        let game_id: String = iter::repeat(())
-            .map(|()| thread_rng().sample(Alphanumeric))
-            .take(7)
-            .collect();
-        self.insert(
-            game_id.clone(),
-            Arc::new(Mutex::new(GameExecutor::from_game(game, game_id.clone()))),
-        );
-        game_id
+/// Describes a game for the lobby's game list.
+pub fn game_metadata(game_executor: &GameExecutor, room: &GameRoom) -> GameMetadata {
+    GameMetadata {
+        game_id: game_executor.game_id.clone(),
+        config: game_executor.game.config.clone(),
+        map_id: game_executor.game.map.name.clone(),
+        password_protected: matches!(room.access, Access::Password(_)),
+        spectators: room.spectators.len() as u32,
+        players: game_executor.game.players.len() as u32,
+        capacity: game_executor.capacity() as u32,
+        status: game_executor.status(),
+        created_at: room.created_at,
+        host: game_executor
+            .game
+            .players
+            .iter()
+            .find(|player| player.host)
+            .map(|player| player.name.clone()),
     }
 }
 
+/// A game's details for `/games/{id}`.
+#[derive(Serialize)]
+pub struct GameDetails {
+    pub game: GameMetadata,
+    pub players: Vec<Player>,
+}
+
+/// A map's summary for `/maps`.
+#[derive(Serialize)]
+pub struct MapSummary {
+    pub map_id: String,
+    pub size: MapSize,
+    pub planets: usize,
+    pub max_players: usize,
+}
+
+/// What a newly accepted connection asked for.
+enum Accepted {
+    Websocket(Box<WebSocketStream<http::Replay<TcpStream>>>),
+    /// A plain HTTP request, answered and then closed
+    Http(http::Request, TcpStream),
+}
+
+/// Most games sent in one `GameList`
+pub const GAME_PAGE_SIZE: u32 = 50;
+/// How long to wait for clients to disconnect when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before accepting again after the listener fails, so
+/// running out of file descriptors doesn't spin the accept loop
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const JSON_CONTENT_TYPE: &str = "application/json";
+/// Why clients are turned away once the connection limit is reached
+const SERVER_FULL: &str = "The server is full, try again later.";
+
 impl GameServer {
     /// Starts a game server
     ///
     /// # Examples
     ///
     /// ```
-    /// let port: u16 = 1234;
+    /// let config = ServerConfig { port: 1234, ..ServerConfig::default() };
     /// // Websocket now can be reached from localhost:1234
-    /// GameServer::start(port);
+    /// GameServer::start(config, maps);
     ///
-    pub fn start(port: u16, maps: impl map_manager::MapManager + Send + 'static) {
-        let instance = GameServer {
-            port: port,
-            connections: Mutex::new(Vec::new()),
+    pub fn start(config: ServerConfig, maps: impl map_manager::MapManager + Send + 'static) {
+        let address = config.address();
+        let metrics_address = config.metrics_address();
+        let instance = GameServer::new(config, maps);
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = match TcpListener::bind(&address).await {
+                Ok(listener) => listener,
+                Err(error) => {
+                    error!("Unable to listen on {}: {}", address, error);
+                    return;
+                }
+            };
+            let metrics_listener = match metrics_address {
+                Some(address) => match TcpListener::bind(&address).await {
+                    Ok(listener) => Some(listener),
+                    Err(error) => {
+                        error!("Unable to serve metrics on {}: {}", address, error);
+                        return;
+                    }
+                },
+                None => None,
+            };
+            instance
+                .serve(listener, metrics_listener, shutdown_signal())
+                .await;
+        });
+    }
+
+    pub fn new(config: ServerConfig, maps: impl map_manager::MapManager + Send + 'static) -> Self {
+        GameServer {
+            config,
+            connections: std::sync::Mutex::new(HashMap::new()),
+            next_connection_id: AtomicUsize::new(0),
+            pending_handshakes: AtomicUsize::new(0),
+            failed_handshakes: AtomicUsize::new(0),
+            outbox_stats: Arc::new(OutboxStats::default()),
+            metrics: Metrics::default(),
             games: RwLock::new(HashMap::new()),
-            rejoin_codes: Mutex::new(HashMap::new()),
             map_manager: Mutex::new(Box::new(maps)),
+            lobby_chat: Mutex::new(ChatLog::default()),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Brings back any saved games, then accepts clients on the listener
+    /// until `shutdown` resolves and shuts the server down. Metrics are
+    /// served on the metrics listener, if there is one.
+    pub async fn serve(
+        self,
+        mut listener: TcpListener,
+        metrics_listener: Option<TcpListener>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let shareable_instance = Arc::new(self);
+        shareable_instance.restore_snapshot();
+        if let Some(metrics_listener) = metrics_listener {
+            tokio::spawn(GameServer::serve_metrics(
+                shareable_instance.clone(),
+                metrics_listener,
+            ));
+        }
+        if shareable_instance.config.snapshot_dir.is_some() {
+            tokio::spawn(GameServer::snapshot_periodically(
+                shareable_instance.clone(),
+            ));
+        }
+        let mut incoming = listener.incoming();
+        futures::pin_mut!(shutdown);
+        loop {
+            tokio::select! {
+                stream = incoming.next() => match stream {
+                    Some(Ok(stream)) => {
+                        let connection_id = shareable_instance
+                            .next_connection_id
+                            .fetch_add(1, Ordering::Relaxed);
+                        let peer = stream
+                            .peer_addr()
+                            .map_or_else(|_| "unknown".to_owned(), |peer| peer.to_string());
+                        let span = info_span!("connection", id = connection_id, peer = %peer);
+                        tokio::spawn(
+                            GameServer::accept_connection(
+                                shareable_instance.clone(),
+                                stream,
+                                connection_id,
+                            )
+                            .instrument(span),
+                        );
+                    }
+                    Some(Err(error)) => {
+                        warn!("Unable to accept connection: {}", error);
+                        tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+                    }
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            }
+        }
+        shareable_instance.shutdown().await;
+    }
+
+    /// Whether the server is disconnecting everyone before it exits. Players
+    /// keep their seats while it does, so they can rejoin after a restart.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Closes every connection, waits for them to finish, and saves the games.
+    async fn shutdown(&self) {
+        info!("Shutting down.");
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.broadcast(Message::Close(None).into());
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while !self.connections.lock().unwrap().is_empty() && Instant::now() < deadline {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        if let Err(error) = self.save_snapshot().await {
+            error!("Unable to save games: {}", error);
+        }
+    }
+
+    async fn snapshot_periodically(instance: Arc<GameServer>) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(instance.config.snapshot_interval));
+        loop {
+            interval.tick().await;
+            if let Err(error) = instance.save_snapshot().await {
+                error!("Unable to save games: {}", error);
+            }
+        }
+    }
+
+    /// Writes every game and rejoin session to the snapshot directory, if one
+    /// is configured. Expired sessions are dropped along the way.
+    pub async fn save_snapshot(&self) -> Result<(), String> {
+        let directory = match &self.config.snapshot_dir {
+            Some(directory) => directory,
+            None => return Ok(()),
         };
-        let mut rt = Runtime::new().unwrap();
-        rt.block_on(async move {
-            let mut listener = TcpListener::bind(&SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-                port,
-            ))
-            .await
-            .unwrap();
-            let shareable_instance = Arc::new(instance);
-            let mut incoming = listener.incoming();
-            while let Some(stream) = incoming.next().await {
-                let stream = stream.unwrap();
-                let ws_stream = accept_async(stream).await;
-                GameServer::handle_stream(shareable_instance.clone(), ws_stream.unwrap()).await;
+        let mut snapshot = ServerSnapshot::default();
+        for game in self.game_handles() {
+            // Games removed in the meantime don't need saving
+            if let Ok(saved) = game.call("Snapshot", GameActor::snapshot).await {
+                snapshot.games.push(saved);
             }
-        });
+        }
+        persistence::save(directory, &snapshot)
+    }
+
+    /// Brings back the games saved before the server last stopped.
+    fn restore_snapshot(self: &Arc<Self>) {
+        let directory = match &self.config.snapshot_dir {
+            Some(directory) => directory,
+            None => return,
+        };
+        let snapshot = match persistence::load(directory) {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => return,
+            Err(error) => {
+                error!("Unable to restore games: {}", error);
+                return;
+            }
+        };
+        info!("Restoring {} games.", snapshot.games.len());
+        let mut games = self.games.write().unwrap();
+        for saved in snapshot.games {
+            let game_executor = GameExecutor::from_snapshot(saved.game);
+            let game = GameActor::spawn(self.clone(), game_executor, saved.room, saved.sessions);
+            games.insert(game.game_id.clone(), game);
+        }
+    }
+
+    /// Hosts a new game, announcing it to the lobby unless it is unlisted.
+    pub fn add_game(
+        self: &Arc<Self>,
+        game: Game,
+        visibility: Visibility,
+    ) -> Result<GameCreated, String> {
+        let mut games = self.games.write().unwrap();
+        if games.len() >= self.config.max_games {
+            return Err("The server is hosting too many games, try again later.".to_owned());
+        }
+        let game_id: String = iter::repeat(())
+            .map(|()| thread_rng().sample(Alphanumeric))
+            .take(7)
+            .collect();
+        let mut game_executor = GameExecutor::from_game(game, game_id.clone());
+        game_executor.tick_length = self.config.tick_length();
+        let room = GameRoom::new(visibility.into());
+        let invite_code = room.invite_code.clone();
+        let metadata = game_metadata(&game_executor, &room);
+        let game = GameActor::spawn(self.clone(), game_executor, room, Sessions::default());
+        let listed = game.listed;
+        games.insert(game_id, game);
+        drop(games);
+        if listed {
+            self.broadcast(encode(MessageType::NewGame(metadata.clone())));
+        }
+        Ok(GameCreated {
+            game: metadata,
+            invite_code,
+        })
+    }
+
+    /// The handle of the game with the given id, if it is still being hosted.
+    pub fn game(&self, game_id: &str) -> Option<GameHandle> {
+        self.games.read().unwrap().get(game_id).cloned()
+    }
+
+    fn game_handles(&self) -> Vec<GameHandle> {
+        self.games.read().unwrap().values().cloned().collect()
+    }
+
+    /// Queues a message for every connection. Clients too far behind to
+    /// take it are disconnected by their outbox rather than slowing down
+    /// everyone else.
+    pub fn broadcast(&self, message: Outgoing) {
+        let connections = self.connections.lock().unwrap();
+        for connection in connections.values() {
+            connection.send(message.clone());
+        }
+    }
+
+    /// A page of the listed games matching the query, newest first.
+    pub fn list_games(&self, query: &GameQuery) -> GameList {
+        let mut matching: Vec<GameMetadata> = self
+            .game_handles()
+            .iter()
+            .filter(|game| game.listed)
+            .map(GameHandle::metadata)
+            .filter(|metadata| query.matches(metadata))
+            .collect();
+        matching.sort_by_key(|game| Reverse(game.created_at));
+        let limit = query.limit.unwrap_or(GAME_PAGE_SIZE).min(GAME_PAGE_SIZE);
+        GameList {
+            total: matching.len() as u32,
+            offset: query.offset,
+            games: matching
+                .into_iter()
+                .skip(query.offset as usize)
+                .take(limit as usize)
+                .collect(),
+        }
+    }
+
+    /// Sends a chat message to every connection.
+    pub async fn post_lobby_chat(&self, message: ChatMessage) {
+        self.lobby_chat.lock().await.push(message.clone());
+        self.broadcast(encode(MessageType::Chat(message)));
+    }
+
+    /// Answers metrics scrapes until the server stops.
+    async fn serve_metrics(instance: Arc<GameServer>, mut listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(GameServer::answer_scrape(instance.clone(), stream));
+                }
+                Err(error) => {
+                    warn!("Unable to accept metrics connection: {}", error);
+                    tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn answer_scrape(instance: Arc<GameServer>, mut stream: TcpStream) {
+        let timeout = Duration::from_secs(instance.config.handshake_timeout);
+        let request = match tokio::time::timeout(timeout, http::read_request(&mut stream)).await {
+            Ok(Ok(request)) => request,
+            _ => return,
+        };
+        let (status, body) = if request.method != "GET" {
+            ("405 Method Not Allowed", String::new())
+        } else if request.path != "/metrics" {
+            ("404 Not Found", String::new())
+        } else {
+            ("200 OK", instance.render_metrics())
+        };
+        let _ = http::respond(&mut stream, status, METRICS_CONTENT_TYPE, &body).await;
+    }
+
+    /// Answers a plain HTTP request on the game listener with JSON.
+    async fn answer_http(instance: Arc<GameServer>, request: http::Request, mut stream: TcpStream) {
+        let (status, body) = instance.route(&request).await;
+        let _ = http::respond(&mut stream, status, JSON_CONTENT_TYPE, &body).await;
+    }
+
+    /// The status and JSON body answering a plain HTTP request on the game
+    /// listener. Unlisted games are not found, as in the lobby.
+    async fn route(&self, request: &http::Request) -> (&'static str, String) {
+        let not_found = (
+            "404 Not Found",
+            json!({ "error": "Not found." }).to_string(),
+        );
+        if request.method != "GET" {
+            let error = json!({ "error": "Only GET is supported." });
+            return ("405 Method Not Allowed", error.to_string());
+        }
+        let path = request.path.split('?').next().unwrap_or_default();
+        match path {
+            "/healthz" if self.is_shutting_down() => (
+                "503 Service Unavailable",
+                json!({ "status": "shutting down" }).to_string(),
+            ),
+            "/healthz" => ("200 OK", json!({ "status": "ok" }).to_string()),
+            "/games" => {
+                let games = self.list_games(&GameQuery::default());
+                ("200 OK", serde_json::to_string(&games).unwrap())
+            }
+            "/maps" => {
+                let maps = self.map_summaries().await;
+                ("200 OK", serde_json::to_string(&maps).unwrap())
+            }
+            _ => match path.strip_prefix("/games/") {
+                Some(game_id) => match self.game_details(game_id).await {
+                    Some(details) => ("200 OK", serde_json::to_string(&details).unwrap()),
+                    None => not_found,
+                },
+                None => not_found,
+            },
+        }
+    }
+
+    async fn game_details(&self, game_id: &str) -> Option<GameDetails> {
+        let game = self.game(game_id).filter(|game| game.listed)?;
+        let players = game
+            .call("GameDetails", |actor| actor.executor.game.players.clone())
+            .await
+            .ok()?;
+        Some(GameDetails {
+            game: game.metadata(),
+            players,
+        })
     }
 
-    pub async fn add_game(&self, game: Game) -> String {
-        let mut games = self.games.write().await;
-        let map_id = game.map.name.clone();
-        let config = game.config.clone();
-        let game_id = games.add_game(game);
-        let message = Message::from(
-            serde_json::to_string(&MessageType::NewGame(GameMetadata {
-                game_id: game_id.clone(),
-                config,
+    /// Every map games can be created on, by id.
+    pub async fn map_summaries(&self) -> Vec<MapSummary> {
+        let mut summaries: Vec<MapSummary> = self
+            .map_manager
+            .lock()
+            .await
+            .maps()
+            .into_iter()
+            .map(|(map_id, map)| MapSummary {
                 map_id,
-            }))
-            .unwrap(),
+                size: map.size.clone(),
+                planets: map.planets.len(),
+                max_players: map.max_players(),
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.map_id.cmp(&b.map_id));
+        summaries
+    }
+
+    /// Every metric, in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut exposition = Exposition::default();
+        exposition.gauge(
+            "ipg_connections",
+            "Clients connected.",
+            self.connections.lock().unwrap().len() as u32,
+        );
+        exposition.gauge(
+            "ipg_pending_handshakes",
+            "Connections that haven't finished the websocket handshake.",
+            self.pending_handshakes.load(Ordering::SeqCst) as u32,
+        );
+        exposition.counter(
+            "ipg_failed_handshakes_total",
+            "Websocket handshakes that failed or timed out.",
+            self.failed_handshakes.load(Ordering::Relaxed) as u64,
+        );
+        let mut games = [("waiting", 0), ("running", 0), ("finished", 0)];
+        for game in self.game_handles() {
+            let index = match game.metadata().status {
+                GameStatus::Waiting => 0,
+                GameStatus::Running => 1,
+                GameStatus::Finished => 2,
+            };
+            games[index].1 += 1;
+        }
+        exposition.labelled(
+            "ipg_games",
+            "gauge",
+            "Games hosted, by status.",
+            "status",
+            &games,
         );
-        self.broadcast(message).await;
-        game_id
+        let outbox = &self.outbox_stats;
+        exposition.counter(
+            "ipg_outbox_coalesced_total",
+            "Queued messages replaced by a newer copy of the same state.",
+            outbox.coalesced.load(Ordering::Relaxed) as u64,
+        );
+        exposition.counter(
+            "ipg_outbox_dropped_total",
+            "Queued messages dropped when a client fell too far behind.",
+            outbox.dropped.load(Ordering::Relaxed) as u64,
+        );
+        exposition.counter(
+            "ipg_outbox_overflows_total",
+            "Clients disconnected for falling too far behind.",
+            outbox.overflowed.load(Ordering::Relaxed) as u64,
+        );
+        self.metrics.expose(&mut exposition);
+        exposition.finish()
     }
 
-    async fn broadcast(&self, message: Message) {
-        let mut connections = self.connections.lock().await;
-        for connection in connections.iter_mut() {
-            // TODO we should record when message sending fails (or even better
-            // retry). Failing silently is not good, since it may make it
-            // difficult to debug any issues that stem from message transport
-            // failure.
-            let _ = connection.send(message.clone()).await;
-            // Per optimization possibilty - currently we wait for every message to bet sent fully before sending this next one.
-            // This would be much slower then concurrently sending all the messages
+    /// Forgets a game whose task has stopped, telling the lobby it is gone.
+    fn forget_game(&self, game_id: &str, listed: bool) {
+        self.games.write().unwrap().remove(game_id);
+        if listed {
+            self.broadcast(encode(MessageType::RemoveGame(game_id.to_owned())));
         }
     }
 
-    pub async fn remove_game(&self, game_id: &String) {
-        let mut games = self.games.write().await;
-        games.remove(game_id);
-        let message = Message::from(
-            serde_json::to_string(&MessageType::RemoveGame(game_id.clone())).unwrap(),
-        );
-        self.broadcast(message).await;
+    /// Performs the websocket handshake for a newly accepted connection, or
+    /// answers it if it is a plain HTTP request. Clients that take too long
+    /// or don't speak HTTP are dropped without holding up anyone else.
+    async fn accept_connection(
+        instance: Arc<GameServer>,
+        mut stream: TcpStream,
+        connection_id: usize,
+    ) {
+        // Pending handshakes count towards the connection limit. Rather than
+        // spend a handshake on turning them away, they are answered over
+        // plain HTTP, which websocket clients report as a failed upgrade.
+        let pending = instance.pending_handshakes.fetch_add(1, Ordering::SeqCst);
+        if pending >= instance.config.max_connections {
+            instance.pending_handshakes.fetch_sub(1, Ordering::SeqCst);
+            instance.handshake_failed("too many pending handshakes");
+            let body = json!({ "error": SERVER_FULL }).to_string();
+            let status = "503 Service Unavailable";
+            let _ = http::respond(&mut stream, status, JSON_CONTENT_TYPE, &body).await;
+            return;
+        }
+        let timeout = Duration::from_secs(instance.config.handshake_timeout);
+        let websocket_config = WebSocketConfig {
+            max_send_queue: None,
+            max_message_size: Some(instance.config.max_message_size),
+            max_frame_size: Some(instance.config.max_message_size),
+        };
+        let handshake = async move {
+            let request = http::read_request(&mut stream).await?;
+            if !request.upgrade {
+                return Ok(Accepted::Http(request, stream));
+            }
+            // The websocket handshake reads the request again
+            let stream = http::Replay::new(request.received, stream);
+            accept_async_with_config(stream, Some(websocket_config))
+                .await
+                .map(|ws_stream| Accepted::Websocket(Box::new(ws_stream)))
+                .map_err(|error| error.to_string())
+        };
+        let handshake = tokio::time::timeout(timeout, handshake).await;
+        instance.pending_handshakes.fetch_sub(1, Ordering::SeqCst);
+        match handshake {
+            Ok(Ok(Accepted::Websocket(ws_stream))) => {
+                GameServer::handle_stream(instance, *ws_stream, connection_id).await
+            }
+            Ok(Ok(Accepted::Http(request, stream))) => {
+                GameServer::answer_http(instance, request, stream).await
+            }
+            Ok(Err(error)) => instance.handshake_failed(&error),
+            Err(_) => instance.handshake_failed("timed out"),
+        }
+    }
+
+    fn handshake_failed(&self, reason: &str) {
+        let failures = self.failed_handshakes.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Handshake failed: {} ({} failed so far)", reason, failures);
     }
 
     /// Handles an incoming websocket stream
     /// It will mutate game state based in incoming messages,
     /// and broadcast the messages the client requires.
     ///
-    async fn handle_stream<'a>(instance: Arc<GameServer>, ws_stream: WebSocketStream<TcpStream>) {
+    async fn handle_stream(
+        instance: Arc<GameServer>,
+        ws_stream: WebSocketStream<http::Replay<TcpStream>>,
+        connection_id: usize,
+    ) {
         let (mut sink, mut stream) = ws_stream.split();
-        // Create mpsc for sending to client so we can share the sender around
-        let (tx, mut rx) = mpsc::channel(128);
+        // Queue messages for the client so they can be sent from anywhere without waiting
+        let outbox = Outbox::new(instance.config.channel_size, instance.outbox_stats.clone());
+        let writer = outbox.clone();
+        let metrics_instance = instance.clone();
         tokio::spawn(async move {
-            while let Some(message) = rx.next().await {
-                let _ = sink.send(message).await;
+            while let Some(message) = writer.next().await {
+                let kind = message.kind();
+                let frame = message.into_frame();
+                let metrics = &metrics_instance.metrics;
+                if let Some(kind) = kind {
+                    metrics.messages_sent.add(kind);
+                }
+                let size = frame.len() as u64;
+                metrics.bytes_sent.fetch_add(size, Ordering::Relaxed);
+                if sink.send(frame).await.is_err() {
+                    break;
+                }
             }
         });
         // New scope to make sure the lock gets dropped immediately
         {
-            instance.connections.lock().await.push(tx.clone());
+            let mut connections = instance.connections.lock().unwrap();
+            if connections.len() >= instance.config.max_connections {
+                outbox.send(encode(MessageType::Error(SERVER_FULL.to_owned())));
+                outbox.send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Again,
+                    reason: SERVER_FULL.into(),
+                })));
+                return;
+            }
+            connections.insert(connection_id, outbox.clone());
         };
-        println!("Connection opened.");
-        tokio::spawn(async move {
-            let mut connection = GameConnection::new(instance.clone(), tx);
+        info!("Connection opened.");
+        let connection = async move {
+            let mut connection =
+                GameConnection::new(instance.clone(), connection_id, outbox.clone());
             connection.handle_new_client().await;
-            while let Some(Ok(message)) = stream.next().await {
-                let message = message;
-                connection.handle_message(&message).await;
+            loop {
+                // Stop listening once the server has nothing more to say to the client
+                let message = tokio::select! {
+                    message = stream.next() => message,
+                    _ = outbox.closed() => break,
+                };
+                match message {
+                    Some(Ok(message)) => connection.handle_message(&message).await,
+                    _ => break,
+                }
             }
             connection.handle_client_exit().await;
-            println!("Connection closed.");
-        });
+            // Lets the writer finish up and drop its half of the socket
+            outbox.send(Message::Close(None));
+            instance.connections.lock().unwrap().remove(&connection_id);
+            info!("Connection closed.");
+        };
+        tokio::spawn(connection.in_current_span());
+    }
+}
+
+/// Resolves when the process is asked to stop, with Ctrl-C or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
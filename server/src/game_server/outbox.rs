@@ -0,0 +1,263 @@
+use ipg_core::protocol::messages::{Envelope, MessageType};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+/// Messages carrying a complete copy of some state, which make any older
+/// message with the same state out of date.
+#[derive(Clone, PartialEq, Debug)]
+enum Snapshot {
+    Game,
+    Players,
+    GameList,
+    GameMetadata(String),
+    Heartbeat,
+}
+
+impl Snapshot {
+    fn of(envelope: &Envelope) -> Option<Snapshot> {
+        // Replies are how clients learn their requests went through, so
+        // they are never dropped.
+        if envelope.request_id.is_some() {
+            return None;
+        }
+        match &envelope.message {
+            MessageType::Game(_) => Some(Snapshot::Game),
+            MessageType::GamePlayers(_) => Some(Snapshot::Players),
+            MessageType::GameList(_) => Some(Snapshot::GameList),
+            MessageType::Heartbeat(_) => Some(Snapshot::Heartbeat),
+            MessageType::UpdateGame(metadata) => {
+                Some(Snapshot::GameMetadata(metadata.game_id.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A websocket frame waiting to be sent to a client.
+#[derive(Clone)]
+pub struct Outgoing {
+    frame: Message,
+    snapshot: Option<Snapshot>,
+    /// The kind of protocol message in the frame, if it holds one
+    kind: Option<&'static str>,
+}
+
+impl Outgoing {
+    pub fn new(envelope: &Envelope, frame: Message) -> Self {
+        Outgoing {
+            frame,
+            snapshot: Snapshot::of(envelope),
+            kind: Some(envelope.message.kind()),
+        }
+    }
+
+    pub fn kind(&self) -> Option<&'static str> {
+        self.kind
+    }
+
+    pub fn into_frame(self) -> Message {
+        self.frame
+    }
+}
+
+impl From<Message> for Outgoing {
+    fn from(frame: Message) -> Self {
+        Outgoing {
+            frame,
+            snapshot: None,
+            kind: None,
+        }
+    }
+}
+
+/// How the server's outbound queues have coped with slow clients since it started.
+#[derive(Default)]
+pub struct OutboxStats {
+    /// Messages replaced by a newer copy of the same state
+    pub coalesced: AtomicUsize,
+    /// Messages thrown away when a client was disconnected for falling behind
+    pub dropped: AtomicUsize,
+    /// Clients disconnected for falling behind
+    pub overflowed: AtomicUsize,
+}
+
+struct Queue {
+    messages: VecDeque<Outgoing>,
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    stats: Arc<OutboxStats>,
+    /// Wakes the writer when a message is queued or the outbox closes
+    queued: Notify,
+    /// Wakes the reader when the outbox closes
+    closed: Notify,
+}
+
+/// A client's queue of outgoing messages. Sending never waits, so one slow
+/// client can't hold up messages to anyone else. Once the queue is full,
+/// out of date copies of state are dropped to make room, and if that isn't
+/// enough the client is disconnected.
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, stats: Arc<OutboxStats>) -> Self {
+        Outbox {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    messages: VecDeque::new(),
+                    closed: false,
+                }),
+                capacity,
+                stats,
+                queued: Notify::new(),
+                closed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Queues a message. Sending a close frame closes the outbox once the
+    /// frame has been sent.
+    pub fn send(&self, message: impl Into<Outgoing>) {
+        let message = message.into();
+        let closing = matches!(message.frame, Message::Close(_));
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        if queue.messages.len() >= self.shared.capacity && !closing {
+            if let Some(snapshot) = &message.snapshot {
+                let before = queue.messages.len();
+                queue
+                    .messages
+                    .retain(|queued| queued.snapshot.as_ref() != Some(snapshot));
+                let coalesced = before - queue.messages.len();
+                self.shared
+                    .stats
+                    .coalesced
+                    .fetch_add(coalesced, Ordering::Relaxed);
+            }
+            if queue.messages.len() >= self.shared.capacity {
+                let dropped = queue.messages.len() + 1;
+                queue.messages.clear();
+                queue.closed = true;
+                drop(queue);
+                let stats = &self.shared.stats;
+                stats.dropped.fetch_add(dropped, Ordering::Relaxed);
+                stats.overflowed.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Disconnecting a client that fell {} messages behind.",
+                    dropped
+                );
+                self.shared.queued.notify();
+                self.shared.closed.notify();
+                return;
+            }
+        }
+        queue.messages.push_back(message);
+        if closing {
+            queue.closed = true;
+        }
+        drop(queue);
+        self.shared.queued.notify();
+        if closing {
+            self.shared.closed.notify();
+        }
+    }
+
+    /// The next message to send, or None once the outbox is closed and empty.
+    pub async fn next(&self) -> Option<Outgoing> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(message) = queue.messages.pop_front() {
+                    return Some(message);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.shared.queued.notified().await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.queue.lock().unwrap().closed
+    }
+
+    /// Resolves once nothing more will be sent to the client.
+    pub async fn closed(&self) {
+        while !self.is_closed() {
+            self.shared.closed.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox(capacity: usize) -> (Outbox, Arc<OutboxStats>) {
+        let stats = Arc::new(OutboxStats::default());
+        (Outbox::new(capacity, stats.clone()), stats)
+    }
+
+    fn message(message: MessageType) -> Outgoing {
+        let envelope = Envelope::from(message);
+        let frame = Message::from(serde_json::to_string(&envelope).unwrap());
+        Outgoing::new(&envelope, frame)
+    }
+
+    fn queued(outbox: &Outbox) -> Vec<Message> {
+        let queue = outbox.shared.queue.lock().unwrap();
+        queue
+            .messages
+            .iter()
+            .map(|message| message.frame.clone())
+            .collect()
+    }
+
+    #[test]
+    fn full_queues_coalesce_snapshots() {
+        let (outbox, stats) = outbox(2);
+        outbox.send(message(MessageType::GamePlayers(Vec::new())));
+        outbox.send(message(MessageType::Pong));
+        outbox.send(message(MessageType::GamePlayers(Vec::new())));
+        assert_eq!(queued(&outbox).len(), 2);
+        assert_eq!(stats.coalesced.load(Ordering::Relaxed), 1);
+        assert!(!outbox.is_closed());
+    }
+
+    #[test]
+    fn overflowing_disconnects() {
+        let (outbox, stats) = outbox(2);
+        outbox.send(message(MessageType::Pong));
+        outbox.send(message(MessageType::Pong));
+        outbox.send(message(MessageType::GamePlayers(Vec::new())));
+        assert!(outbox.is_closed());
+        assert!(queued(&outbox).is_empty());
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.overflowed.load(Ordering::Relaxed), 1);
+        // Nothing is queued once the client is gone
+        outbox.send(message(MessageType::Pong));
+        assert!(queued(&outbox).is_empty());
+    }
+
+    #[test]
+    fn close_frames_are_sent_before_closing() {
+        let (outbox, _) = outbox(1);
+        outbox.send(message(MessageType::Pong));
+        outbox.send(Message::Close(None));
+        assert!(outbox.is_closed());
+        assert_eq!(queued(&outbox).len(), 2);
+    }
+}
@@ -0,0 +1,121 @@
+use super::game_actor::GameRoom;
+use super::rejoin::Sessions;
+use ipg_core::game::crash::CrashDump;
+use ipg_core::game::GameSnapshot;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_FILE: &str = "games.json";
+
+/// The games being hosted.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ServerSnapshot {
+    pub games: Vec<SavedGame>,
+}
+
+/// A game along with what the server keeps about it besides the
+/// simulation, and the sessions players can rejoin it with.
+#[derive(Serialize, Deserialize)]
+pub struct SavedGame {
+    pub game: GameSnapshot,
+    pub room: GameRoom,
+    pub sessions: Sessions,
+}
+
+/// Writes the snapshot to the directory, replacing the previous one.
+pub fn save(directory: &str, snapshot: &ServerSnapshot) -> Result<(), String> {
+    let directory = Path::new(directory);
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("Unable to create {}: {}", directory.display(), e))?;
+    let contents = serde_json::to_string(snapshot)
+        .map_err(|e| format!("Unable to serialize snapshot: {}", e))?;
+    // Write to a separate file first, so a crash mid-write can't corrupt the last snapshot
+    let temporary = directory.join(format!("{}.tmp", SNAPSHOT_FILE));
+    fs::write(&temporary, contents)
+        .map_err(|e| format!("Unable to write {}: {}", temporary.display(), e))?;
+    fs::rename(&temporary, directory.join(SNAPSHOT_FILE))
+        .map_err(|e| format!("Unable to replace snapshot: {}", e))
+}
+
+/// Reads the snapshot saved in the directory, if there is one.
+pub fn load(directory: &str) -> Result<Option<ServerSnapshot>, String> {
+    let path = Path::new(directory).join(SNAPSHOT_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| format!("Invalid snapshot {}: {}", path.display(), e))
+}
+
+/// Writes a crashed game's dump to the directory, returning where it was saved.
+pub fn save_crash_dump(directory: &str, dump: &CrashDump) -> Result<PathBuf, String> {
+    let directory = Path::new(directory);
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("Unable to create {}: {}", directory.display(), e))?;
+    let contents = serde_json::to_string(dump)
+        .map_err(|e| format!("Unable to serialize crash dump: {}", e))?;
+    let path = directory.join(format!("{}-{}.json", dump.game_id, dump.crashed_at));
+    fs::write(&path, contents).map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_server::access::Access;
+    use crate::game_server::tests::running_game;
+    use ipg_core::game::{get_millis, GameExecutor};
+    use ipg_core::protocol::messages::Visibility;
+    use std::env;
+    use std::process;
+
+    const LIFETIME: u128 = 60_000;
+
+    #[test]
+    fn saved_games_are_restored() {
+        let directory = env::temp_dir().join(format!("ipg-persistence-{}", process::id()));
+        let directory = directory.to_str().unwrap();
+        let executor = running_game("game");
+        let room = GameRoom::new(Access::from(Visibility::Password("secret".to_owned())));
+        let mut sessions = Sessions::default();
+        let rejoin_code = sessions.issue(0, "Ada", get_millis(), LIFETIME);
+        let tick = executor.current_tick();
+        let snapshot = ServerSnapshot {
+            games: vec![SavedGame {
+                game: executor.snapshot(),
+                room: room.clone(),
+                sessions,
+            }],
+        };
+        save(directory, &snapshot).unwrap();
+        let mut loaded = load(directory).unwrap().unwrap();
+        fs::remove_dir_all(directory).unwrap();
+
+        let mut saved = loaded.games.pop().unwrap();
+        let restored = GameExecutor::from_snapshot(saved.game);
+        assert_eq!(restored.game_id, "game");
+        // The clock carries on from where it was, rather than starting over
+        assert!(restored.current_tick() >= tick);
+        assert_eq!(
+            serde_json::to_string(&restored.game.state).unwrap(),
+            serde_json::to_string(&executor.game.state).unwrap()
+        );
+        // Nobody is connected until they rejoin
+        assert!(restored
+            .game
+            .players
+            .iter()
+            .all(|player| player.disconnected));
+        let session = saved.sessions.resume(&rejoin_code, get_millis(), LIFETIME);
+        assert_eq!(session.unwrap().possession, 0);
+        assert_eq!(saved.room.invite_code, room.invite_code);
+        match saved.room.access {
+            Access::Password(hash) => assert!(hash.matches("secret")),
+            _ => panic!("The game lost its password"),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use crate::config::ServerConfig;
+use ipg_core::protocol::messages::MessageType;
+use std::collections::VecDeque;
+
+/// Milliseconds over which rate limit violations are counted
+const VIOLATION_WINDOW: u128 = 60_000;
+
+/// Allows `capacity` actions at once, refilling at a steady rate afterwards.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Tokens regained per millisecond
+    refill_rate: f64,
+    last_refill: u128,
+}
+
+impl TokenBucket {
+    /// A full bucket that refills completely every `period` milliseconds.
+    pub fn new(capacity: u32, period: u128, now: u128) -> Self {
+        let capacity = capacity as f64;
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_rate: capacity / period as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token if there is one left.
+    pub fn take(&mut self, now: u128) -> bool {
+        let elapsed = now.saturating_sub(self.last_refill) as f64;
+        self.tokens = (self.tokens + elapsed * self.refill_rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What a rate limited message was, and whether the connection has been
+/// over its limits often enough to be dropped.
+#[derive(Debug)]
+pub enum Violation {
+    Limited(String),
+    Disconnect(String),
+}
+
+/// Stops a single connection from flooding the server. Moves and new games
+/// are the expensive requests, so each has its own limit on top of the one
+/// shared by every message.
+pub struct RateLimiter {
+    messages: TokenBucket,
+    moves: TokenBucket,
+    games: TokenBucket,
+    violations: VecDeque<u128>,
+    max_violations: usize,
+}
+
+impl RateLimiter {
+    pub fn new(config: &ServerConfig, now: u128) -> Self {
+        RateLimiter {
+            messages: TokenBucket::new(config.messages_per_second, 1000, now),
+            moves: TokenBucket::new(config.moves_per_second, 1000, now),
+            games: TokenBucket::new(config.games_per_minute, 60_000, now),
+            violations: VecDeque::new(),
+            max_violations: config.max_rate_limit_violations as usize,
+        }
+    }
+
+    /// Records a message received at `now`, unless the connection is over its limit.
+    pub fn check(&mut self, message: &MessageType, now: u128) -> Result<(), Violation> {
+        let allowed = self.messages.take(now)
+            && match message {
+                MessageType::GameMove(_) => self.moves.take(now),
+                MessageType::CreateGame(_) => self.games.take(now),
+                _ => true,
+            };
+        if allowed {
+            return Ok(());
+        }
+        while let Some(&time) = self.violations.front() {
+            if now.saturating_sub(time) < VIOLATION_WINDOW {
+                break;
+            }
+            self.violations.pop_front();
+        }
+        self.violations.push_back(now);
+        if self.violations.len() > self.max_violations {
+            Err(Violation::Disconnect(
+                "Disconnected for sending messages too quickly.".to_owned(),
+            ))
+        } else {
+            Err(Violation::Limited(format!(
+                "You are sending {} requests too quickly.",
+                message.kind()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipg_core::protocol::messages::GameMove;
+
+    fn limiter() -> RateLimiter {
+        let config = ServerConfig {
+            messages_per_second: 10,
+            moves_per_second: 2,
+            max_rate_limit_violations: 3,
+            ..ServerConfig::default()
+        };
+        RateLimiter::new(&config, 0)
+    }
+
+    fn game_move() -> MessageType {
+        MessageType::GameMove(GameMove { from: 0, to: 1 })
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut bucket = TokenBucket::new(2, 1000, 0);
+        assert!(bucket.take(0));
+        assert!(bucket.take(0));
+        assert!(!bucket.take(0));
+        assert!(!bucket.take(499));
+        assert!(bucket.take(500));
+        // Unused time doesn't let the bucket overflow
+        assert!(bucket.take(100_000));
+        assert!(bucket.take(100_000));
+        assert!(!bucket.take(100_000));
+    }
+
+    #[test]
+    fn categories_have_separate_limits() {
+        let mut limiter = limiter();
+        assert!(limiter.check(&game_move(), 0).is_ok());
+        assert!(limiter.check(&game_move(), 0).is_ok());
+        assert!(limiter.check(&game_move(), 0).is_err());
+        assert!(limiter.check(&MessageType::Ping, 0).is_ok());
+    }
+
+    #[test]
+    fn repeated_violations_disconnect() {
+        let mut limiter = limiter();
+        for _ in 0..10 {
+            let _ = limiter.check(&MessageType::Ping, 0);
+        }
+        for _ in 0..3 {
+            assert!(matches!(
+                limiter.check(&MessageType::Ping, 0),
+                Err(Violation::Limited(_))
+            ));
+        }
+        assert!(matches!(
+            limiter.check(&MessageType::Ping, 0),
+            Err(Violation::Disconnect(_))
+        ));
+        // Violations are forgotten after a while
+        let later = VIOLATION_WINDOW + 50;
+        for _ in 0..10 {
+            assert!(limiter.check(&MessageType::Ping, later).is_ok());
+        }
+        assert!(matches!(
+            limiter.check(&MessageType::Ping, later),
+            Err(Violation::Limited(_))
+        ));
+    }
+
+    #[test]
+    fn clock_stepping_back_keeps_violations() {
+        let mut limiter = limiter();
+        for _ in 0..13 {
+            let _ = limiter.check(&MessageType::Ping, 1000);
+        }
+        assert!(matches!(
+            limiter.check(&MessageType::Ping, 500),
+            Err(Violation::Disconnect(_))
+        ));
+    }
+}
@@ -1,10 +1,161 @@
+use ipg_core::protocol::messages::RejoinCode;
+use rand::rngs::OsRng;
 use rand::{distributions::Alphanumeric, Rng};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-pub fn generate_rejoin_code() -> String {
-    let s: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(7)
-        .map(char::from)
-        .collect();
-    return s;
+/// Session tokens are long enough that they can't be guessed.
+pub fn generate_session_token() -> RejoinCode {
+    generate_code(32)
+}
+
+/// Invite codes let anyone who has them into unlisted games, so they are
+/// secrets just like session tokens.
+pub fn generate_invite_code() -> String {
+    generate_code(12)
+}
+
+/// Codes come straight from the operating system's random number generator.
+fn generate_code(length: usize) -> String {
+    let mut rng = OsRng::new().expect("Unable to access the operating system's random numbers.");
+    rng.sample_iter(&Alphanumeric).take(length).collect()
+}
+
+/// A player's claim on a seat in a game.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub possession: usize,
+    /// The player the session was issued to
+    pub name: String,
+    /// When the session stops working if it isn't used, in milliseconds
+    /// since the unix epoch. Sessions don't expire while connected.
+    pub expires_at: u128,
+    /// Whether a connection is currently using the seat. Nobody is connected
+    /// to a restored game, so this isn't saved.
+    #[serde(skip)]
+    pub connected: bool,
+}
+
+/// The sessions issued for one game.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Sessions {
+    sessions: HashMap<RejoinCode, Session>,
+}
+
+impl Sessions {
+    /// Gives a newly seated player a session. Anyone previously issued the
+    /// same possession has lost their seat, so their sessions are revoked.
+    pub fn issue(
+        &mut self,
+        possession: usize,
+        name: &str,
+        now: u128,
+        lifetime: u128,
+    ) -> RejoinCode {
+        self.revoke_possession(possession);
+        let token = generate_session_token();
+        self.sessions.insert(
+            token.clone(),
+            Session {
+                possession,
+                name: name.to_owned(),
+                expires_at: now + lifetime,
+                connected: true,
+            },
+        );
+        token
+    }
+
+    /// Takes back the seat a session was issued for.
+    pub fn resume(&mut self, token: &str, now: u128, lifetime: u128) -> Result<Session, String> {
+        self.remove_expired(now);
+        let session = self
+            .sessions
+            .get_mut(token)
+            .ok_or_else(|| "Your session has expired or is not valid for this game.".to_owned())?;
+        if session.connected {
+            return Err("This seat is already connected elsewhere.".to_owned());
+        }
+        session.connected = true;
+        session.expires_at = now + lifetime;
+        Ok(session.clone())
+    }
+
+    /// Marks the session's seat as free, starting the session's expiry.
+    pub fn disconnect(&mut self, token: &str, now: u128, lifetime: u128) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.connected = false;
+            session.expires_at = now + lifetime;
+        }
+    }
+
+    pub fn revoke_possession(&mut self, possession: usize) {
+        self.sessions
+            .retain(|_, session| session.possession != possession);
+    }
+
+    pub fn remove_expired(&mut self, now: u128) {
+        self.sessions
+            .retain(|_, session| session.connected || session.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIFETIME: u128 = 1000;
+
+    #[test]
+    fn session_tokens_are_unique() {
+        let token = generate_session_token();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, generate_session_token());
+    }
+
+    #[test]
+    fn connected_seat_cannot_be_resumed() {
+        let mut sessions = Sessions::default();
+        let token = sessions.issue(1, "Ada", 0, LIFETIME);
+        assert!(sessions.resume(&token, 10, LIFETIME).is_err());
+        sessions.disconnect(&token, 20, LIFETIME);
+        let session = sessions.resume(&token, 30, LIFETIME).unwrap();
+        assert_eq!(session.possession, 1);
+        assert_eq!(session.name, "Ada");
+    }
+
+    #[test]
+    fn sessions_expire_after_disconnecting() {
+        let mut sessions = Sessions::default();
+        let token = sessions.issue(0, "Ada", 0, LIFETIME);
+        // Connected sessions outlive their expiry
+        sessions.remove_expired(5 * LIFETIME);
+        assert!(!sessions.sessions.is_empty());
+        sessions.disconnect(&token, 5 * LIFETIME, LIFETIME);
+        assert!(sessions.resume(&token, 6 * LIFETIME + 1, LIFETIME).is_err());
+        assert!(sessions.sessions.is_empty());
+    }
+
+    #[test]
+    fn resuming_refreshes_expiry() {
+        let mut sessions = Sessions::default();
+        let token = sessions.issue(0, "Ada", 0, LIFETIME);
+        sessions.disconnect(&token, 0, LIFETIME);
+        sessions.resume(&token, LIFETIME - 1, LIFETIME).unwrap();
+        sessions.disconnect(&token, LIFETIME - 1, LIFETIME);
+        sessions.remove_expired(LIFETIME + 1);
+        assert!(!sessions.sessions.is_empty());
+    }
+
+    #[test]
+    fn reissuing_a_possession_revokes_old_sessions() {
+        let mut sessions = Sessions::default();
+        let old = sessions.issue(0, "Ada", 0, LIFETIME);
+        sessions.disconnect(&old, 0, LIFETIME);
+        let other = sessions.issue(1, "Grace", 0, LIFETIME);
+        sessions.issue(0, "Linus", 0, LIFETIME);
+        assert!(sessions.resume(&old, 1, LIFETIME).is_err());
+        sessions.disconnect(&other, 1, LIFETIME);
+        assert!(sessions.resume(&other, 2, LIFETIME).is_ok());
+    }
 }
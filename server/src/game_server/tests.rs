@@ -0,0 +1,565 @@
+//! Tests that talk to a server running in the same process over a real socket.
+
+use super::game_actor::GameActor;
+use super::map_manager::{FileSystemMapManager, MapManager};
+use super::{GameServer, GAME_PAGE_SIZE};
+use crate::config::ServerConfig;
+use futures::{SinkExt, StreamExt};
+use ipg_core::game::{DisconnectPolicy, Game, GameConfig, GameExecutor, Player};
+use ipg_core::protocol::messages::{
+    Envelope, GameList, GameMetadata, GameMove, GameQuery, MessageType, Visibility,
+};
+use ipg_native_client::{ClientError, GameClient};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, WebSocketStream};
+
+type Client = WebSocketStream<TcpStream>;
+
+/// How long to wait for the server to answer before failing the test
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a server on a free port, which keeps running until the test ends.
+async fn start_server(config: ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let maps = FileSystemMapManager::new("../maps".to_owned());
+    let server = GameServer::new(config, maps);
+    tokio::spawn(server.serve(listener, None, futures::future::pending()));
+    address
+}
+
+/// Starts a server that also serves metrics, returning the addresses of
+/// both listeners.
+async fn start_server_with_metrics(config: ServerConfig) -> (SocketAddr, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let metrics_address = metrics_listener.local_addr().unwrap();
+    let maps = FileSystemMapManager::new("../maps".to_owned());
+    let server = GameServer::new(config, maps);
+    let shutdown = futures::future::pending();
+    tokio::spawn(server.serve(listener, Some(metrics_listener), shutdown));
+    (address, metrics_address)
+}
+
+/// Sends a GET request, returning the whole response.
+async fn http_get(address: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("Timed out waiting for the server")
+        .unwrap();
+    response
+}
+
+/// The body of a response.
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+/// Connects a client that has set its name.
+async fn join(address: SocketAddr, name: &str) -> GameClient {
+    let url = format!("ws://{}", address);
+    let mut client = GameClient::connect(&url).await.unwrap();
+    client.set_name(name).await.unwrap();
+    client
+}
+
+/// Creates a game on Trio for two or more players, returning its id.
+async fn create_game(client: &mut GameClient, visibility: Visibility) -> String {
+    let config = GameConfig {
+        min_players: 2,
+        disconnect_policy: DisconnectPolicy::Forfeit,
+    };
+    client
+        .create_private_game("Trio", config, visibility)
+        .await
+        .unwrap();
+    // The game was announced before the request was acknowledged
+    let created = client.state().invite_codes.keys().next().cloned();
+    created.expect("The server didn't say which game it created")
+}
+
+/// Reads what the server sends until a message matches, returning it.
+async fn wait_for(client: &mut GameClient, wanted: impl Fn(&MessageType) -> bool) -> MessageType {
+    loop {
+        let envelope = tokio::time::timeout(TIMEOUT, client.next())
+            .await
+            .expect("Timed out waiting for the server")
+            .expect("The server closed the connection")
+            .unwrap();
+        if wanted(&envelope.message) {
+            return envelope.message;
+        }
+    }
+}
+
+/// Whether the server refused the request for the given reason.
+fn refused(result: Result<(), ClientError>, reason: &str) -> bool {
+    matches!(result, Err(ClientError::Rejected { message, .. }) if message.contains(reason))
+}
+
+/// Connects a raw websocket, to send the server frames a client wouldn't.
+async fn connect(address: SocketAddr) -> Client {
+    let (client, _) = connect_async(format!("ws://{}", address)).await.unwrap();
+    client
+}
+
+async fn send(client: &mut Client, request_id: u32, message: MessageType) {
+    let envelope = Envelope {
+        request_id: Some(request_id),
+        message,
+    };
+    let frame = Message::from(serde_json::to_string(&envelope).unwrap());
+    client.send(frame).await.unwrap();
+}
+
+/// Collects the replies to requests, skipping everything else the server sends.
+/// Stops when the server closes the connection or `count` replies have arrived.
+async fn read_replies(client: &mut Client, count: usize) -> (Vec<MessageType>, bool) {
+    let mut replies = Vec::new();
+    let mut closed = false;
+    while replies.len() < count {
+        let message = tokio::time::timeout(TIMEOUT, client.next())
+            .await
+            .expect("Timed out waiting for the server");
+        match message {
+            Some(Ok(Message::Text(text))) => {
+                let envelope: Envelope = serde_json::from_str(&text).unwrap();
+                if envelope.request_id.is_some() {
+                    replies.push(envelope.message);
+                }
+            }
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                closed = true;
+                break;
+            }
+            Some(Ok(_)) => (),
+        }
+    }
+    (replies, closed)
+}
+
+fn rate_limited(reply: &MessageType) -> bool {
+    matches!(reply, MessageType::Error(error) if error.contains("too quickly"))
+}
+
+fn test_config() -> ServerConfig {
+    ServerConfig {
+        maps_dir: Some("../maps".to_owned()),
+        ..ServerConfig::default()
+    }
+}
+
+/// A running game between two players, started two seconds ago. Shared
+/// with the tests that use a game directly rather than over a socket.
+pub(super) fn running_game(game_id: &str) -> GameExecutor {
+    let maps = FileSystemMapManager::new("../maps".to_owned());
+    let map = maps.map_by_id(&"Trio".to_owned()).unwrap().clone();
+    let config = GameConfig {
+        min_players: 2,
+        disconnect_policy: DisconnectPolicy::Forfeit,
+    };
+    let mut executor = GameExecutor::from_game(Game::new(map, config), game_id.to_owned());
+    for name in &["Ada", "Grace"] {
+        let player = executor
+            .add_player(Player {
+                name: name.to_string(),
+                possession: 0,
+                ready: false,
+                host: false,
+                disconnected: false,
+                bot: false,
+            })
+            .unwrap();
+        executor.set_ready(player.possession, true).unwrap();
+    }
+    executor.start_game().unwrap();
+    executor.start_time -= 2000;
+    executor
+}
+
+#[tokio::test]
+async fn moves_are_rate_limited() {
+    let address = start_server(ServerConfig {
+        moves_per_second: 2,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(address).await;
+    for request_id in 0..5 {
+        send(
+            &mut client,
+            request_id,
+            MessageType::GameMove(GameMove { from: 0, to: 1 }),
+        )
+        .await;
+    }
+    let (replies, closed) = read_replies(&mut client, 5).await;
+    assert!(!closed);
+    assert_eq!(
+        replies.iter().filter(|reply| rate_limited(reply)).count(),
+        3
+    );
+    // Other requests aren't held up by the move limit
+    send(&mut client, 5, MessageType::Ping).await;
+    let (replies, _) = read_replies(&mut client, 1).await;
+    assert!(matches!(replies[0], MessageType::Ack));
+}
+
+#[tokio::test]
+async fn flooding_clients_are_disconnected() {
+    let address = start_server(ServerConfig {
+        messages_per_second: 5,
+        max_rate_limit_violations: 3,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(address).await;
+    for request_id in 0..20 {
+        send(&mut client, request_id, MessageType::Ping).await;
+    }
+    let (replies, closed) = read_replies(&mut client, 20).await;
+    assert!(closed);
+    assert_eq!(
+        replies.iter().filter(|reply| rate_limited(reply)).count(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn games_per_connection_are_capped() {
+    let address = start_server(ServerConfig {
+        max_games_per_connection: 1,
+        ..test_config()
+    })
+    .await;
+    let mut client = join(address, "Host").await;
+    create_game(&mut client, Visibility::Public).await;
+    let config = GameConfig {
+        min_players: 2,
+        disconnect_policy: DisconnectPolicy::Forfeit,
+    };
+    let created = client.create_game("Trio", config).await;
+    assert!(refused(created, "more than 1"));
+}
+
+#[tokio::test]
+async fn oversized_messages_disconnect() {
+    let address = start_server(ServerConfig {
+        max_message_size: 1024,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(address).await;
+    let _ = client.send(Message::Text("x".repeat(2048))).await;
+    let (_, closed) = read_replies(&mut client, 1).await;
+    assert!(closed);
+}
+
+#[tokio::test]
+async fn metrics_can_be_scraped() {
+    let (address, metrics_address) = start_server_with_metrics(test_config()).await;
+    let mut client = join(address, "Host").await;
+    client.request(MessageType::Ping).await.unwrap();
+    // Not in a game, so this is rejected
+    assert!(client.game_move(0, 1).await.is_err());
+    create_game(&mut client, Visibility::Public).await;
+
+    let response = http_get(metrics_address, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    for sample in &[
+        "ipg_connections 1\n",
+        "ipg_games{status=\"waiting\"} 1\n",
+        "ipg_games{status=\"running\"} 0\n",
+        "ipg_messages_received_total{kind=\"Ping\"} 1\n",
+        "ipg_messages_received_total{kind=\"GameMove\"} 1\n",
+        "ipg_messages_sent_total{kind=\"Pong\"} 1\n",
+        "ipg_request_errors_total{kind=\"GameMove\"} 1\n",
+        "ipg_move_duration_seconds_count 0\n",
+        "ipg_failed_handshakes_total 0\n",
+    ] {
+        assert!(
+            response.contains(sample),
+            "{} missing from\n{}",
+            sample,
+            response
+        );
+    }
+
+    let response = http_get(metrics_address, "/other").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[tokio::test]
+async fn http_is_answered_beside_websockets() {
+    let address = start_server(test_config()).await;
+    let mut client = join(address, "Host").await;
+    create_game(&mut client, Visibility::Public).await;
+
+    let response = http_get(address, "/healthz").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Type: application/json"));
+    assert_eq!(body(&response), r#"{"status":"ok"}"#);
+
+    let response = http_get(address, "/games").await;
+    let games: GameList = serde_json::from_str(body(&response)).unwrap();
+    assert_eq!(games.total, 1);
+    let game_id = &games.games[0].game_id;
+
+    let response = http_get(address, &format!("/games/{}", game_id)).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(body(&response).contains(r#""status":"Waiting""#));
+    assert!(body(&response).contains(r#""players":["#));
+
+    let response = http_get(address, "/maps").await;
+    assert!(body(&response).contains(r#""map_id":"Trio""#));
+
+    let response = http_get(address, "/games/missing").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+    // The websocket is still served after the plain requests
+    client.request(MessageType::Ping).await.unwrap();
+    join(address, "Guest").await;
+}
+
+/// The lobby's view of a game, as served over HTTP.
+async fn listed_game(address: SocketAddr, game_id: &str) -> Option<GameMetadata> {
+    let response = http_get(address, "/games").await;
+    let games: GameList = serde_json::from_str(body(&response)).unwrap();
+    games.games.into_iter().find(|game| game.game_id == game_id)
+}
+
+#[tokio::test]
+async fn entering_a_game_leaves_the_last_one() {
+    let address = start_server(test_config()).await;
+    let mut first_host = join(address, "First").await;
+    let first = create_game(&mut first_host, Visibility::Public).await;
+    first_host.enter_game(&first).await.unwrap();
+    let mut second_host = join(address, "Second").await;
+    let second = create_game(&mut second_host, Visibility::Public).await;
+    second_host.enter_game(&second).await.unwrap();
+
+    let mut watcher = join(address, "Watcher").await;
+    watcher.spectate_game(&first, None).await.unwrap();
+    watcher.spectate_game(&first, None).await.unwrap();
+    let game = listed_game(address, &first).await.unwrap();
+    assert_eq!(game.spectators, 1);
+    watcher.enter_game(&second).await.unwrap();
+    let game = listed_game(address, &first).await.unwrap();
+    assert_eq!(game.spectators, 0);
+    assert_eq!(listed_game(address, &second).await.unwrap().players, 2);
+
+    // Players can't watch their own game
+    let spectated = first_host.spectate_game(&first, None).await;
+    assert!(refused(spectated, "playing in"));
+    // The first game empties once its only player moves on
+    first_host.enter_game(&second).await.unwrap();
+    assert!(listed_game(address, &first).await.is_none());
+    assert_eq!(listed_game(address, &second).await.unwrap().players, 3);
+}
+
+#[tokio::test]
+async fn slow_handshakes_time_out() {
+    let (address, metrics_address) = start_server_with_metrics(ServerConfig {
+        handshake_timeout: 1,
+        ..test_config()
+    })
+    .await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+    // The server gives up on the request and closes the connection
+    let mut received = Vec::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut received))
+        .await
+        .expect("Timed out waiting for the server")
+        .unwrap();
+    assert!(received.is_empty());
+    let response = http_get(metrics_address, "/metrics").await;
+    assert!(response.contains("ipg_failed_handshakes_total 1\n"));
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_turned_away() {
+    let address = start_server(ServerConfig {
+        max_connections: 1,
+        ..test_config()
+    })
+    .await;
+    let _connected = join(address, "Player").await;
+    let mut client = connect(address).await;
+    let message = tokio::time::timeout(TIMEOUT, client.next())
+        .await
+        .expect("Timed out waiting for the server");
+    let envelope = match message {
+        Some(Ok(Message::Text(text))) => serde_json::from_str::<Envelope>(&text).unwrap(),
+        _ => panic!("The server didn't say why the connection was refused"),
+    };
+    assert!(matches!(envelope.message, MessageType::Error(error) if error.contains("full")));
+    let (_, closed) = read_replies(&mut client, 1).await;
+    assert!(closed);
+}
+
+#[tokio::test]
+async fn pending_handshakes_over_the_limit_are_turned_away() {
+    let address = start_server(ServerConfig {
+        max_connections: 1,
+        ..test_config()
+    })
+    .await;
+    // Holds the only handshake slot by never finishing its request
+    let mut stalled = TcpStream::connect(address).await.unwrap();
+    stalled.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    let response = http_get(address, "/healthz").await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+    assert!(body(&response).contains("The server is full"));
+}
+
+#[tokio::test]
+async fn only_the_host_manages_the_game() {
+    let address = start_server(test_config()).await;
+    let mut host = join(address, "Host").await;
+    let game_id = create_game(&mut host, Visibility::Public).await;
+    host.enter_game(&game_id).await.unwrap();
+    let mut guest = join(address, "Guest").await;
+    guest.enter_game(&game_id).await.unwrap();
+
+    assert!(refused(guest.start_game().await, "Only the host"));
+    assert!(refused(guest.kick_player(0).await, "Only the host"));
+    let possession = guest.state().current_game.as_ref().unwrap().possession;
+    host.kick_player(possession.unwrap() as u32).await.unwrap();
+    let kicked = wait_for(&mut guest, |message| {
+        matches!(message, MessageType::Kicked(_))
+    });
+    assert!(matches!(kicked.await, MessageType::Kicked(kicked) if kicked == game_id));
+    // Kicked players are no longer in the game
+    assert!(refused(
+        guest.set_ready(true).await,
+        "not currently in a game"
+    ));
+}
+
+#[tokio::test]
+async fn hosting_passes_on_when_the_host_leaves() {
+    let address = start_server(test_config()).await;
+    let mut host = join(address, "Host").await;
+    let game_id = create_game(&mut host, Visibility::Public).await;
+    host.enter_game(&game_id).await.unwrap();
+    let mut guest = join(address, "Guest").await;
+    guest.enter_game(&game_id).await.unwrap();
+
+    host.request(MessageType::ExitGame).await.unwrap();
+    let players = wait_for(
+        &mut guest,
+        |message| matches!(message, MessageType::GamePlayers(players) if players.len() == 1),
+    );
+    match players.await {
+        MessageType::GamePlayers(players) => {
+            assert_eq!(players[0].name, "Guest");
+            assert!(players[0].host);
+        }
+        _ => unreachable!(),
+    }
+    // The new host can run the game, e.g. kick players who join
+    let mut late = join(address, "Late").await;
+    late.enter_game(&game_id).await.unwrap();
+    let possession = late.state().current_game.as_ref().unwrap().possession;
+    guest.kick_player(possession.unwrap() as u32).await.unwrap();
+}
+
+#[tokio::test]
+async fn unlisted_games_are_joined_by_invite() {
+    let address = start_server(test_config()).await;
+    let mut host = join(address, "Host").await;
+    let game_id = create_game(&mut host, Visibility::Unlisted).await;
+    let invite_code = host.state().invite_codes[&game_id].clone();
+
+    let mut guest = join(address, "Guest").await;
+    guest.query_games(GameQuery::default()).await.unwrap();
+    assert!(guest
+        .state()
+        .games
+        .iter()
+        .all(|game| game.game_id != game_id));
+    assert!(listed_game(address, &game_id).await.is_none());
+    assert!(refused(guest.enter_game(&game_id).await, "Could not find"));
+    guest
+        .state_mut()
+        .invite_codes
+        .insert(game_id.clone(), invite_code);
+    guest.enter_game(&game_id).await.unwrap();
+}
+
+#[tokio::test]
+async fn password_protected_games_need_the_password() {
+    let address = start_server(test_config()).await;
+    let mut host = join(address, "Host").await;
+    let password = Visibility::Password("secret".to_owned());
+    let game_id = create_game(&mut host, password).await;
+    let game = listed_game(address, &game_id).await.unwrap();
+    assert!(game.password_protected);
+
+    let mut guest = join(address, "Guest").await;
+    let entered = guest.enter_game_with_password(&game_id, None).await;
+    assert!(refused(entered, "requires a password"));
+    let entered = guest
+        .enter_game_with_password(&game_id, Some("guess"))
+        .await;
+    assert!(refused(entered, "Incorrect password"));
+    let entered = guest
+        .enter_game_with_password(&game_id, Some("secret"))
+        .await;
+    entered.unwrap();
+}
+
+#[tokio::test]
+async fn game_lists_are_paged_newest_first() {
+    let maps = FileSystemMapManager::new("../maps".to_owned());
+    let map = maps.map_by_id(&"Trio".to_owned()).unwrap().clone();
+    let server = Arc::new(GameServer::new(test_config(), maps));
+    for created_at in 0..60 {
+        let config = GameConfig {
+            min_players: 2,
+            disconnect_policy: DisconnectPolicy::Forfeit,
+        };
+        let created = server.add_game(Game::new(map.clone(), config), Visibility::Public);
+        let game = server.game(&created.unwrap().game.game_id).unwrap();
+        // Games made this quickly would otherwise share a creation time
+        let backdate = move |actor: &mut GameActor| actor.room.created_at = created_at;
+        game.call("Backdate", backdate).await.unwrap();
+    }
+    let created_at =
+        |list: GameList| -> Vec<u128> { list.games.iter().map(|game| game.created_at).collect() };
+
+    // Pages are no larger than the server's page size, however many are asked for
+    let list = server.list_games(&GameQuery {
+        limit: Some(100),
+        ..GameQuery::default()
+    });
+    assert_eq!(list.total, 60);
+    assert_eq!(list.games.len(), GAME_PAGE_SIZE as usize);
+    assert_eq!(created_at(list), (10..60).rev().collect::<Vec<_>>());
+    let list = server.list_games(&GameQuery {
+        offset: 55,
+        limit: Some(3),
+        ..GameQuery::default()
+    });
+    assert_eq!(list.offset, 55);
+    assert_eq!(created_at(list), vec![4, 3, 2]);
+    let list = server.list_games(&GameQuery {
+        offset: 100,
+        ..GameQuery::default()
+    });
+    assert_eq!(list.total, 60);
+    assert!(list.games.is_empty());
+}
@@ -0,0 +1,20 @@
+use crate::config::ServerConfig;
+use tracing_subscriber::filter::LevelFilter;
+
+/// Sends log events to stdout, at the configured level and in the
+/// configured format. JSON lines include the spans the event happened in,
+/// e.g. the connection and game.
+pub fn init(config: &ServerConfig) {
+    // The level was validated along with the rest of the config
+    let level = config.log_level.parse().unwrap_or(LevelFilter::INFO);
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    if config.log_format == "json" {
+        builder
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .init();
+    } else {
+        builder.init();
+    }
+}
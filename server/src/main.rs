@@ -2,31 +2,30 @@
 extern crate serde_derive;
 extern crate tokio;
 
+mod config;
 mod game_server;
+mod logging;
 
+use self::config::{Options, ServerConfig};
 use self::game_server::map_manager;
 use self::game_server::GameServer;
+use structopt::StructOpt;
 
 fn main() {
-    println!("Hello, world!");
-    bootstrap_game_servers()
+    let config = match ServerConfig::load(Options::from_args()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    logging::init(&config);
+    bootstrap_game_servers(config)
 }
 
 /// Starts up the game server
-fn bootstrap_game_servers() {
-    GameServer::start(
-        get_port(),
-        map_manager::FileSystemMapManager::new(get_maps_dir()),
-    );
-}
-
-fn get_maps_dir() -> String {
-    std::env::var("IPG_MAPS_DIR").expect("The IPG_MAPS_DIR environment variable must be set.")
-}
-
-fn get_port() -> u16 {
-    std::env::var("IPG_PORT")
-        .expect("The IPG_PORT environment variable must be set.")
-        .parse::<u16>()
-        .expect("The IPG_PORT enironment variable must be a number")
+fn bootstrap_game_servers(config: ServerConfig) {
+    // Validation makes sure the maps directory is set
+    let maps_dir = config.maps_dir.clone().unwrap();
+    GameServer::start(config, map_manager::FileSystemMapManager::new(maps_dir));
 }
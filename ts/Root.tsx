@@ -48,8 +48,14 @@ function Root() {
                 )
             );
             const join = parsed.get("join");
+            const invite_code = parsed.get("invite");
             if (join) {
-                const game = games.find(game => game.game_id === join);
+                const listed = games.find(game => game.game_id === join);
+                // Unlisted games aren't in the list, the server sends their
+                // details once we have joined with the invite code
+                const game = invite_code ?
+                    { map_id: "", config: { min_players: 2 }, ...listed, game_id: join, invite_code }
+                    : listed;
                 // todo display error if game is not found
                 if (game) {
                     setMode(mode => { 
@@ -7,15 +7,27 @@ interface Props {
 }
 
 function GameForm() {
+    const modeContext = useContext(ModeContext);
     useEffect(() => {
         const remove = gameConnectionSingleton.onEvent("MapList", () => {
             setMapList(gameConnectionSingleton.client.get_maps());
         });
-        return remove;
+        // Take the creator straight to their game, since unlisted games don't show up in the list
+        const removeCreated = gameConnectionSingleton.onEvent("GameCreated", () => {
+            const game = gameConnectionSingleton.client.get_created_game();
+            modeContext.setMode({ type: "game", game });
+        });
+        return () => {
+            remove();
+            removeCreated();
+        };
     }, []);
     const [mapList, setMapList] = useState(gameConnectionSingleton.client.get_maps());
     const [minPlayers, setMinPlayers] = useState(2);
     const [mapId, setMapId] = useState("");
+    const [visibility, setVisibility] = useState("Public");
+    const [password, setPassword] = useState("");
+    const [disconnectPolicy, setDisconnectPolicy] = useState("Forfeit");
     const previewCanvas = useRef<HTMLCanvasElement>(null);
     useEffect(() => {
         const canvas = previewCanvas.current;
@@ -33,10 +45,14 @@ function GameForm() {
     ]);
     const createGame = () => {
         gameConnectionSingleton.socket.send(JSON.stringify({
-            "CreateGame": {
-                map_id: mapId,
-                config: {
-                    min_players: minPlayers
+            message: {
+                "CreateGame": {
+                    map_id: mapId,
+                    config: {
+                        min_players: minPlayers,
+                        disconnect_policy: disconnectPolicy
+                    },
+                    visibility: visibility === "Password" ? { "Password": password } : visibility
                 }
             }
         }));
@@ -57,6 +73,21 @@ function GameForm() {
                         return <option key={map_id} value={map_id}>{map_id}</option>;
                     })}
                 </select>
+                <label>Visibility</label>
+                <select onChange={e => setVisibility(e.target.value)} value={visibility}>
+                    <option value="Public">Public</option>
+                    <option value="Unlisted">Unlisted (invite only)</option>
+                    <option value="Password">Password protected</option>
+                </select>
+                {visibility === "Password" ?
+                    <input type="password" onChange={e => setPassword(e.target.value)} value={password} />
+                    : undefined}
+                <label>When a Player Disconnects</label>
+                <select onChange={e => setDisconnectPolicy(e.target.value)} value={disconnectPolicy}>
+                    <option value="Forfeit">They forfeit</option>
+                    <option value="Neutral">Their planets become neutral</option>
+                    <option value="Bot">A bot takes over</option>
+                </select>
                 <div className="button" onClick={createGame}>New Game!</div>
             </div>
             <div>
@@ -16,10 +16,17 @@ function GameItem(props: Props) {
 
     const modeContext = useContext(ModeContext);
 
-    const handleGameSelection = (game: game) => {
+    const handleGameSelection = (game: game, spectate = false) => {
+        if (game.password_protected) {
+            const password = window.prompt("This game is password protected. Password:");
+            if (password === null) {
+                return;
+            }
+            game = { ...game, password };
+        }
         modeContext.setMode({
             type: "game",
-            game
+            game: { ...game, spectate }
         })
     };
     const preview = useRef(null);
@@ -38,6 +45,14 @@ function GameItem(props: Props) {
                 </canvas>
                 <div className="card-inside">
                     {props.game.game_id}
+                    {props.game.password_protected ? " (password)" : undefined}
+                    <div>{props.game.status} - {props.game.players}/{props.game.capacity} players
+                        {props.game.host ? `, hosted by ${props.game.host}` : undefined}</div>
+                    {props.game.spectators ? ` - ${props.game.spectators} watching` : undefined}
+                    <span className="button" onClick={e => {
+                        e.stopPropagation();
+                        handleGameSelection(props.game, true);
+                    }}>Watch</span>
                 </div>
             </div>
     </>;
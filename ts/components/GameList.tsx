@@ -8,14 +8,49 @@ interface Props {
 
 }
 
+// Matches the server's page size
+const pageSize = 50;
+
 function GameList() {
     const [games, setGames] = useGameList();
+    const [status, setStatus] = useState("");
+    const [openSlots, setOpenSlots] = useState(false);
+    const [offset, setOffset] = useState(0);
+    const total = gameConnectionSingleton.client.game_list_total();
+    useEffect(() => {
+        if (gameConnectionSingleton.status === "open") {
+            gameConnectionSingleton.client.query_games({
+                status: status || null,
+                open_slots: openSlots,
+                offset
+            });
+        }
+    }, [status, openSlots, offset]);
     return <div className="inner-content start-page">
         <GameForm />
+        <div className="game-list-filters">
+            <select onChange={e => { setStatus(e.target.value); setOffset(0); }} value={status}>
+                <option value="">All games</option>
+                <option value="Waiting">Waiting</option>
+                <option value="Running">Running</option>
+                <option value="Finished">Finished</option>
+            </select>
+            <label>
+                <input type="checkbox" checked={openSlots}
+                    onChange={e => { setOpenSlots(e.target.checked); setOffset(0); }} />
+                Open slots only
+            </label>
+            {offset > 0 ?
+                <span className="button" onClick={() => setOffset(Math.max(0, offset - pageSize))}>Previous</span>
+                : undefined}
+            {offset + pageSize < total ?
+                <span className="button" onClick={() => setOffset(offset + pageSize)}>Next</span>
+                : undefined}
+        </div>
         <div className="game-list">
             {games.map((game) => (<GameItem key={game.game_id} game={game} />))}
         </div>
     </div>;
 }
 
-export default GameList;
\ No newline at end of file
+export default GameList;
@@ -1,6 +1,7 @@
-import React, { useEffect, useRef, useState, MouseEvent } from 'react';
+import React, { useContext, useEffect, useRef, useState, MouseEvent } from 'react';
 import { gameConnectionSingleton } from "../connection/index";
 import { playerColors, gameUrl } from '../gameInfo';
+import { ModeContext } from '../state/mode';
 
 interface Props {
     game: any
@@ -10,11 +11,38 @@ function GameWindow(props: Props) {
     const canvasTop = useRef<HTMLCanvasElement>(null);
     const canvasBottom = useRef<HTMLCanvasElement>(null);
     const [players, setPlayersInternal] = useState<any[]>([]);
-    const setPlayers = () => setPlayersInternal(gameConnectionSingleton.client.get_player_list());
+    const [isHost, setIsHost] = useState(false);
+    const [playing, setPlaying] = useState(false);
+    const [standings, setStandings] = useState<any[]>([]);
+    const [heir, setHeir] = useState("");
+    const setPlayers = () => {
+        setPlayersInternal(gameConnectionSingleton.client.get_player_list());
+        setIsHost(gameConnectionSingleton.client.is_host());
+        setPlaying(gameConnectionSingleton.client.get_possession() !== undefined);
+        setStandings(gameConnectionSingleton.client.get_standings() || []);
+    };
+    const [game, setGame] = useState(props.game);
+    const [ready, setReady] = useState(false);
     const [gameStarted, setGameStarted] = useState(false);
+    const modeContext = useContext(ModeContext);
     const startGame = () => {
         gameConnectionSingleton.client.start_game();
     }
+    const toggleReady = () => {
+        gameConnectionSingleton.client.set_ready(!ready);
+        setReady(!ready);
+    }
+    const kickPlayer = (possession: number) => {
+        gameConnectionSingleton.client.kick_player(possession);
+    }
+    const surrender = () => {
+        if (window.confirm("Give up the game? You'll keep watching it.")) {
+            gameConnectionSingleton.client.surrender(heir === "" ? undefined : parseInt(heir));
+        }
+    }
+    const changeMap = (mapId: string) => {
+        gameConnectionSingleton.client.update_game_settings(mapId, game.config.min_players);
+    }
     const getCoordinates = (e: MouseEvent) => {
         // This is awful:
         const rect = (Array.from(canvasTop.current!.parentElement!.children)
@@ -33,19 +61,15 @@ function GameWindow(props: Props) {
         gameConnectionSingleton.client.mouse_down(x, y);
     }
     useEffect(() => {
-        gameConnectionSingleton.client.enter_game(props.game, canvasTop.current, canvasBottom.current);
+        gameConnectionSingleton.client.enter_game(props.game, canvasTop.current, canvasBottom.current,
+            props.game.password, props.game.invite_code, !!props.game.spectate);
         // game is implictly started when the first GameState is sent
         let renderStarted = false;
         const unHookGameEvent = gameConnectionSingleton.onEvent("Game", () => {
             if (!renderStarted) {
-                const gameTimeFrames = gameConnectionSingleton.client.get_time(); // warning: nullable
-                const startTimeMilliseconds = Date.now() - (gameTimeFrames! * 17);
-                console.log(`Started at: ` + startTimeMilliseconds);
+                // The client tracks the server's clock, so each frame renders the current game tick
                 const render = () => {
-                    const time = ~~((Date.now() - startTimeMilliseconds) / 17);
-                    if (time >= 0) {
-                        gameConnectionSingleton.client.render_game_frame(time);
-                    }
+                    gameConnectionSingleton.client.render_game_frame();
                     window.requestAnimationFrame(render);
                 };
                 window.requestAnimationFrame(render);
@@ -54,33 +78,72 @@ function GameWindow(props: Props) {
             setGameStarted(true);
             setPlayers();
         });
-        const unHookGamePlayersEvent = gameConnectionSingleton.onEvent("GamePlayers", setPlayers);
+        const unHookGamePlayersEvent = gameConnectionSingleton.onEvent("GamePlayers", () => {
+            setPlayers();
+            // Settings changes and host changes clear the ready flags
+            setReady(false);
+        });
+        const unHookUpdateGameEvent = gameConnectionSingleton.onEvent("UpdateGame", () => {
+            const waiting = gameConnectionSingleton.client.get_waiting_game();
+            if (waiting) {
+                setGame(waiting);
+            }
+        });
+        const unHookKickedEvent = gameConnectionSingleton.onEvent("Kicked", () => {
+            modeContext.setMode({ type: "browse" });
+        });
+        const unHookEliminatedEvent = gameConnectionSingleton.onEvent("Eliminated", setPlayers);
         return () => {
             unHookGameEvent();
             unHookGamePlayersEvent();
+            unHookUpdateGameEvent();
+            unHookKickedEvent();
+            unHookEliminatedEvent();
         };
     }, [canvasTop, canvasBottom, props.game]);
-    const canStart = props.game.config.min_players <= players.length;
+    const canStart = game.config.min_players <= players.length;
+    const allReady = players.every(player => player.host || player.ready);
     return (
         <>
             <div style={{
                 "position": "relative"
             }}>
                 <div className="game-waiting">
-                    {gameStarted ? undefined : <>
+                    {gameStarted ? undefined : props.game.spectate ?
+                        <div>
+                            <h2>Waiting for the game to start...</h2>
+                            <div>{players.length} players have joined.</div>
+                        </div>
+                        : <>
                         {!canStart ?
                             <div>
                                 <h2>Waiting for more players to join...</h2>
-                                <div>({players.length}/{props.game.config.min_players})
+                                <div>({players.length}/{game.config.min_players})
                                 players have joined.</div>
-                                <div>Invite your friends! <input readOnly value={gameUrl(props.game).toString()} /></div>
+                                <div>Invite your friends! <input readOnly value={gameUrl(game, gameConnectionSingleton.client.get_invite_code(game.game_id)).toString()} /></div>
                             </div>
                             :
                             <div>
                                 <h2>Ready to begin...</h2>
                                 <div>{players.length} players have joined.</div>
-                                <div onClick={startGame} className={["button", !canStart ? "disabled" : ""].join(" ")}>Start Game!</div>
+                                {isHost ?
+                                    <div onClick={startGame} className={["button", !allReady ? "disabled" : ""].join(" ")}>
+                                        {allReady ? "Start Game!" : "Waiting for players to get ready..."}
+                                    </div>
+                                    : undefined}
+                            </div>
+                        }
+                        {isHost ?
+                            <div>
+                                <label>Map</label>
+                                <select onChange={e => changeMap(e.target.value)} value={game.map_id}>
+                                    {gameConnectionSingleton.client.get_maps().map((map_id: string) => {
+                                        return <option key={map_id} value={map_id}>{map_id}</option>;
+                                    })}
+                                </select>
                             </div>
+                            :
+                            <div onClick={toggleReady} className="button">{ready ? "Not Ready" : "Ready!"}</div>
                         }
                     </>}
                 </div>
@@ -98,8 +161,33 @@ function GameWindow(props: Props) {
                         <div
                             className="player-color"
                             style={{ backgroundColor: playerColors[player.possession + 1] }} />
-                        {player.name}</div>;
+                        {player.name}
+                        {player.host ? " (host)" : player.ready && !gameStarted ? " (ready)" : ""}
+                        {player.disconnected ? " (disconnected)" : player.bot ? " (bot)" : ""}
+                        {isHost && !player.host && !gameStarted ?
+                            <span className="button" onClick={() => kickPlayer(player.possession)}>Kick</span>
+                            : undefined}
+                    </div>;
                 })}
+                {gameStarted && playing ?
+                    <div>
+                        <select onChange={e => setHeir(e.target.value)} value={heir}>
+                            <option value="">Leave my planets neutral</option>
+                            {players.filter(player => player.possession !== gameConnectionSingleton.client.get_possession()).map(player =>
+                                <option key={player.possession} value={player.possession}>Leave my planets to {player.name}</option>
+                            )}
+                        </select>
+                        <span className="button" onClick={surrender}>Surrender</span>
+                    </div>
+                    : undefined}
+                {standings.length > 0 ?
+                    <>
+                        <h4>Standings</h4>
+                        {[...standings].sort((a, b) => a.placement - b.placement).map(standing =>
+                            <div key={standing.possession}>{standing.placement}. {standing.name}</div>
+                        )}
+                    </>
+                    : undefined}
             </div>
         </>
     );
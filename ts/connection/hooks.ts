@@ -11,9 +11,11 @@ export const useGameList = () => {
         };
         const remove1 = gameConnectionSingleton.onEvent("GameList", updateGames);
         const remove2 = gameConnectionSingleton.onEvent("NewGame", updateGames);
+        const remove3 = gameConnectionSingleton.onEvent("UpdateGame", updateGames);
         return () => {
             remove1();
             remove2();
+            remove3();
         };
     },[setGames]);
     return state;
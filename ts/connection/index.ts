@@ -24,10 +24,11 @@ function configureSocket(socket: WebSocket, client: GameClient, connection: Game
     socket.addEventListener("open",() => {
         connection.setStatus("open");
         clearTimeout(connectingNotice);
+        client.sync_clock();
         // Send a message to prevent cloudflare from timing out hte connection.
         // Cloudflare times out after 100 seconds, so we are sending a keep alive every 60.
         const interval = setInterval(() => {
-            socket.send(JSON.stringify("Ping"));
+            socket.send(JSON.stringify({ message: "Ping" }));
         }, 1000 * 60 /* one minute */);
         socket.addEventListener("close",() => clearInterval(interval));
         delayRef.ref = 1000;
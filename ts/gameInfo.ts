@@ -12,6 +12,7 @@ export const playerColors = [
     "#a565e0", // Lavender
 ];
 
-export const gameUrl = (game: any): URL => {
-    return new URL(`${window.location.origin}#join=${game.game_id}`);
+export const gameUrl = (game: any, inviteCode?: string): URL => {
+    const invite = inviteCode ? `;invite=${inviteCode}` : "";
+    return new URL(`${window.location.origin}#join=${game.game_id}${invite}`);
 }
\ No newline at end of file
@@ -3,6 +3,16 @@ import React from "react";
 export type game = {
     game_id: string,
     map_id: string,
+    password_protected?: boolean,
+    spectators?: number,
+    players?: number,
+    capacity?: number,
+    status?: "Waiting" | "Running" | "Finished",
+    host?: string,
+    // Used when entering the game, not sent by the server
+    password?: string,
+    invite_code?: string,
+    spectate?: boolean,
 };
 
 export type mode = {